@@ -1,21 +1,44 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 /// Add a writeable overlay on top of read-only files
 #[derive(Debug, Parser)]
 #[command(version)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Arguments {
-    /// Where original read-only data should be read from
+    /// Where original read-only data should be read from (a file, block device, split or compressed image, `http://` URL or `nbd://` export)
     #[arg(short, long, value_name = "FILE")]
     pub seed_file: PathBuf,
 
+    /// Only use the part of the seed starting at this offset (suffixes like `1M` or `4G` are accepted)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+    pub seed_offset: u64,
+
+    /// Only use this many bytes of the seed, starting at the seed offset (the rest of the seed by default)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub seed_length: Option<u64>,
+
     /// Where modified (written) data should be stored
-    #[arg(short, long, value_name = "FILE")]
-    pub overlay_file: PathBuf,
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present = "container_file"
+    )]
+    pub overlay_file: Option<PathBuf>,
 
     /// Where a mask of the modified data should be stored
-    #[arg(short, long, value_name = "FILE")]
-    pub mask_file: PathBuf,
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present = "container_file"
+    )]
+    pub mask_file: Option<PathBuf>,
+
+    /// Where both the overlay and mask should be stored (instead of separate files)
+    #[arg(short, long, value_name = "FILE", conflicts_with_all = ["overlay_file", "mask_file"])]
+    pub container_file: Option<PathBuf>,
 
     /// Block size for all read and write operations
     #[arg(short, long, value_name = "BYTES", default_value_t = 512)]
@@ -25,10 +48,100 @@ pub struct Arguments {
     #[arg(short, long)]
     pub ignore_errors: bool,
 
+    /// Where the session manifest should be stored (mask or container file path + `.manifest` by default)
+    #[arg(long, value_name = "FILE")]
+    pub manifest_file: Option<PathBuf>,
+
+    /// Continue even if the seed doesn't match the session manifest
+    #[arg(long)]
+    pub ignore_manifest: bool,
+
+    /// Record checksums of seed blocks as they are read (recorded checksums are always verified)
+    #[arg(long)]
+    pub checksums: bool,
+
+    /// Where seed checksums should be stored (mask or container file path + `.checksums` by default)
+    #[arg(long, value_name = "FILE")]
+    pub checksum_file: Option<PathBuf>,
+
+    /// Where blocks of an `http://` seed should be cached (mask or container file path + `.http-cache` by default)
+    #[arg(long, value_name = "FILE")]
+    pub http_cache_file: Option<PathBuf>,
+
+    /// Other copies of the seed to read sectors the seed can't read from, tried in order
+    #[arg(long, value_name = "FILE")]
+    pub seed_mirror: Vec<PathBuf>,
+
+    /// Cache this many bytes of seed data in memory (0 to disable)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+    pub seed_cache: u64,
+
+    /// Read this many more bytes when sequential seed reads miss the cache
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "256K")]
+    pub read_ahead: u64,
+
+    /// Retry failed reads of a seed sector this many times before considering it unreadable
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    pub read_retries: u32,
+
+    /// Delay before retrying a failed seed read, doubled for every following retry
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 100)]
+    pub retry_delay: u64,
+
+    /// Where unreadable seed ranges should be recorded (mask or container file path + `.badblocks` by default)
+    #[arg(long, value_name = "FILE")]
+    pub bad_block_file: Option<PathBuf>,
+
+    /// Fail reads of unreadable seed sectors instead of returning zeroes for them
+    #[arg(long, requires = "ignore_errors")]
+    pub fail_unreadable: bool,
+
+    /// Encrypt the overlay and mask with a key derived from the contents of this file
+    #[arg(long, value_name = "FILE", conflicts_with = "passphrase")]
+    pub key_file: Option<PathBuf>,
+
+    /// Encrypt the overlay and mask with a passphrase (from `OVERMASK_PASSPHRASE` or prompted for)
+    #[arg(long)]
+    pub passphrase: bool,
+
+    /// Where encryption parameters should be stored (mask or container file path + `.encryption` by default)
+    #[arg(long, value_name = "FILE")]
+    pub encryption_file: Option<PathBuf>,
+
+    /// Only print warnings and errors (twice for errors only)
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Print debugging information (twice for trace output)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// How progress of long-running modes should be reported
+    #[arg(long, value_name = "MODE", default_value = "auto")]
+    pub progress: ProgressMode,
+
     #[command(subcommand)]
     pub subcommand: MainSubcommand,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Progress bar when stderr is a terminal, text otherwise
+    Auto,
+
+    /// Progress bar on stderr
+    Bar,
+
+    /// Newline-delimited JSON events on stdout
+    Json,
+
+    /// Log lines with percentages
+    Text,
+
+    /// No progress output
+    None,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum MainSubcommand {
     /// Apply the overlay on top of the seed using the mask
@@ -36,6 +149,14 @@ pub enum MainSubcommand {
     Apply {
         #[arg(long)]
         force: bool,
+
+        /// Continue from the last checkpoint instead of the first block
+        #[arg(short, long)]
+        resume: bool,
+
+        /// Where apply progress should be saved (mask or container file path + `.checkpoint` by default)
+        #[arg(short, long, value_name = "FILE")]
+        checkpoint_file: Option<PathBuf>,
     },
 
     /// Deduplicate data between the seed and overlay
@@ -46,6 +167,52 @@ pub enum MainSubcommand {
         truncate: bool,
     },
 
+    /// Reclaim overlay space that is no longer covered by the mask
+    #[command(visible_aliases = ["g"])]
+    Gc,
+
+    /// Create the overlay, mask and manifest files for a new session
+    #[command(visible_aliases = ["i"])]
+    Init {
+        /// Allocate disk space for the overlay and mask files up front
+        #[arg(short, long, conflicts_with = "sparse")]
+        preallocate: bool,
+
+        /// Set the overlay and mask file sizes to the seed size without allocating
+        #[arg(short, long)]
+        sparse: bool,
+
+        /// Record checksums of every seed block (implies --checksums)
+        #[arg(long)]
+        hash: bool,
+
+        /// Store overlay data zstd-compressed (with an index at mask or container file path + `.index`)
+        #[arg(short, long)]
+        compress: bool,
+
+        /// Store each unique overlay block once (with maps at mask or container file path + `.dedup` and `.dedup-blocks`)
+        #[arg(short, long)]
+        deduplicate: bool,
+    },
+
+    /// Print information about the session
+    Info,
+
+    /// Copy the overlay and mask to a container file or back to separate files
+    Convert {
+        /// Container file to create
+        #[arg(long, value_name = "FILE", required_unless_present = "to_overlay_file", conflicts_with_all = ["to_overlay_file", "to_mask_file"])]
+        to_container_file: Option<PathBuf>,
+
+        /// Overlay file to create
+        #[arg(long, value_name = "FILE", requires = "to_mask_file")]
+        to_overlay_file: Option<PathBuf>,
+
+        /// Mask file to create
+        #[arg(long, value_name = "FILE", requires = "to_overlay_file")]
+        to_mask_file: Option<PathBuf>,
+    },
+
     /// Create a virtual block device to capture writes
     #[command(visible_aliases = ["d", "dev"])]
     Device {
@@ -64,5 +231,95 @@ pub enum MainSubcommand {
         /// Don't punch holes (fallocate) in overlay and mask on `trim()`
         #[arg(short = 'T', long)]
         trim_no_punch_holes: bool,
+
+        /// Size of the virtual device, to grow it past the end of the seed (seed size by default)
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        size: Option<u64>,
+
+        /// Copy seed data into the overlay as it's read, so every sector is read from the seed only once
+        #[arg(short = 'C', long)]
+        copy_on_read: bool,
+
+        /// Copy all remaining seed data into the overlay in the background, until the seed is no longer needed
+        #[arg(long)]
+        hydrate: bool,
+
+        /// Limit background hydration to this many bytes per second read from the seed (0 for no limit)
+        #[arg(
+            long,
+            value_name = "SIZE",
+            value_parser = parse_size,
+            default_value = "16M",
+            requires = "hydrate"
+        )]
+        hydrate_rate: u64,
     },
 }
+
+/// Parse a size in bytes, with an optional binary suffix (`K`, `M`, `G`, `T` or `P`, optionally
+/// followed by `iB` or `B`)
+fn parse_size(size: &str) -> Result<u64, String> {
+    let digits = size
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, suffix) = size.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {size:?}"))?;
+
+    let suffix = suffix.to_ascii_uppercase();
+    let unit = suffix
+        .strip_suffix("IB")
+        .filter(|unit| !unit.is_empty())
+        .or_else(|| suffix.strip_suffix('B'))
+        .unwrap_or(&suffix);
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        "P" => 50,
+        _ => {
+            return Err(format!(
+                "invalid size suffix {suffix:?} (expected K, M, G, T or P)"
+            ));
+        }
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {size:?} is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_with_suffixes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("256K"), Ok(262_144));
+        assert_eq!(parse_size("256k"), Ok(262_144));
+        assert_eq!(parse_size("16MiB"), Ok(16_777_216));
+        assert_eq!(parse_size("4GB"), Ok(4 << 30));
+        assert_eq!(parse_size("2T"), Ok(2 << 40));
+        assert_eq!(parse_size("1P"), Ok(1 << 50));
+    }
+
+    #[test]
+    fn overflowing_sizes() {
+        assert!(parse_size("18446744073709551615").is_ok());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("16384P").is_err());
+        assert_eq!(parse_size("16383P"), Ok(16383 << 50));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for size in ["", "K", "-1", "1.5G", "1 G", "1X", "1KK", "1iB", "1E", " 1"] {
+            assert!(parse_size(size).is_err(), "{size}");
+        }
+    }
+}
@@ -1,31 +1,106 @@
-use crate::{Files, MASK};
-use nix::fcntl::{FallocateFlags, fallocate};
+use crate::{Files, MASK, ZEROED, bounded_len};
+use log::{debug, error};
 use std::{
-    fs,
-    io::{self, Write},
-    os::unix::fs::FileExt,
-    path::PathBuf,
+    fs, io,
+    path::Path,
     process::exit,
+    sync::{Arc, Mutex, PoisonError},
 };
 use vblk::BlockDevice;
 
 pub struct Virtual {
-    pub files: Files,
-    pub print_operations: bool,
+    pub files: Arc<Files>,
+    /// Held while changing the overlay and mask, which background hydration does concurrently
+    pub lock: Arc<Mutex<()>>,
     pub trim_no_punch_holes: bool,
+    /// Size of the device, data past the end of the seed lives only in the overlay
+    pub size: u64,
+    /// Copy seed data into the overlay as it's read, so it's read from the seed only once
+    pub copy_on_read: bool,
+    /// Mark trimmed ranges as zeroed instead of going back to the seed, so hydration sticks
+    pub hydrating: bool,
+
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+    pub zero_writes: u64,
+    pub trims: u64,
+    pub bytes_copied: u64,
+}
+
+impl Virtual {
+    fn copy_seed(&mut self, seed_buffer: &[u8], mask_buffer: &[u8], offset: u64) {
+        match copy_seed(&self.files, seed_buffer, mask_buffer, offset) {
+            Ok(copied) => self.bytes_copied += copied,
+            Err(error) => error!(
+                "couldn't copy {} bytes of seed data at offset {offset} to overlay: {error}",
+                seed_buffer.len()
+            ),
+        }
+    }
+
+    /// Read seed data, returning whether it was read successfully (and can be copied on read)
+    fn read_seed(&self, buffer: &mut [u8], offset: u64) -> io::Result<bool> {
+        if let Err(error) = self.files.seed.read_at(buffer, offset) {
+            error!(
+                "couldn't read {} bytes from seed file at offset {offset}: {error}",
+                buffer.len(),
+            );
+            if !self.files.ignore_errors || self.files.seed.fail_unreadable {
+                return Err(error);
+            }
+            return Ok(false);
+        }
+
+        if let Some(checksums) = &self.files.checksums
+            && let Err(error) = checksums.check(offset, buffer)
+        {
+            error!("{error}");
+            return Err(error);
+        }
+        Ok(true)
+    }
+
+    fn read_overlay(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        if let Err(error) = self.files.overlay.read_at(buffer, offset) {
+            error!(
+                "couldn't read {} bytes from overlay file at offset {offset}: {error}",
+                buffer.len(),
+            );
+            if !self.files.ignore_errors {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark a range as zeroed in the mask and deallocate it in the overlay
+    fn write_zeroes(&self, offset: u64, len: usize) -> io::Result<()> {
+        if let Err(error) = self.files.mask.write_all_at(&vec![ZEROED; len], offset) {
+            error!("couldn't write {len} bytes to mask file at offset {offset}: {error}");
+            if !self.files.ignore_errors {
+                return Err(error);
+            }
+        }
+        if let Err(error) = self.files.overlay.punch_hole(offset, len as u64) {
+            error!("couldn't punch hole of size {len} in overlay file at offset {offset}: {error}");
+        }
+        Ok(())
+    }
 }
 
 impl BlockDevice for Virtual {
     fn read(&mut self, offset: u64, bytes: &mut [u8]) -> io::Result<()> {
-        if self.print_operations {
-            println!("read(offset={offset} bytes={})", bytes.len());
-        }
+        debug!(target: "overmask::operations", "read(offset={offset} bytes={})", bytes.len());
+        self.reads += 1;
+        let lock = Arc::clone(&self.lock);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
 
         let mut buffer = vec![0; bytes.len()];
         let mut mask_buffer = vec![0; bytes.len()];
         if let Err(error) = self.files.mask.read_at(&mut mask_buffer, offset) {
-            eprintln!(
-                "overmask: couldn't read {} bytes from mask file at offset {offset}: {error}",
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
                 bytes.len(),
             );
             if !self.files.ignore_errors {
@@ -33,51 +108,33 @@ impl BlockDevice for Virtual {
             }
         }
         if mask_buffer.iter().all(|&byte| byte == 0) {
-            if let Err(error) = self.files.seed.read_at(&mut buffer, offset) {
-                eprintln!(
-                    "overmask: couldn't read {} bytes from seed file at offset {offset}: {error}",
-                    bytes.len(),
-                );
-                if !self.files.ignore_errors {
-                    return Err(error);
-                }
+            if self.read_seed(&mut buffer, offset)? && self.copy_on_read {
+                self.copy_seed(&buffer, &mask_buffer, offset);
             }
         } else if mask_buffer.iter().all(|&byte| byte == MASK) {
-            if let Err(error) = self.files.overlay.read_at(&mut buffer, offset) {
-                eprintln!(
-                    "overmask: couldn't read {} bytes from overlay file at offset {offset}: {error}",
-                    bytes.len(),
-                );
-                if !self.files.ignore_errors {
-                    return Err(error);
-                }
-            }
-        } else {
-            if let Err(error) = self.files.seed.read_at(&mut buffer, offset) {
-                eprintln!(
-                    "overmask: couldn't read {} bytes from seed file at offset {offset}: {error}",
-                    bytes.len(),
-                );
-                if !self.files.ignore_errors {
-                    return Err(error);
-                }
+            self.read_overlay(&mut buffer, offset)?;
+        } else if !mask_buffer.iter().all(|&byte| byte == ZEROED) {
+            let mut seed_buffer = vec![0; bytes.len()];
+            if mask_buffer.contains(&0)
+                && self.read_seed(&mut seed_buffer, offset)?
+                && self.copy_on_read
+            {
+                self.copy_seed(&seed_buffer, &mask_buffer, offset);
             }
             let mut overlay_buffer = vec![0; bytes.len()];
-            if let Err(error) = self.files.overlay.read_at(&mut overlay_buffer, offset) {
-                eprintln!(
-                    "overmask: couldn't read {} bytes from overlay file at offset {offset}: {error}",
-                    bytes.len(),
-                );
-                if !self.files.ignore_errors {
-                    return Err(error);
-                }
+            if mask_buffer.contains(&MASK) {
+                self.read_overlay(&mut overlay_buffer, offset)?;
             }
 
-            buffer = buffer
+            buffer = seed_buffer
                 .iter()
                 .zip(overlay_buffer.iter())
                 .zip(mask_buffer.iter())
-                .map(|((&seed, &overlay), &mask)| if mask == MASK { overlay } else { seed })
+                .map(|((&seed, &overlay), &mask)| match mask {
+                    MASK => overlay,
+                    ZEROED => 0,
+                    _ => seed,
+                })
                 .collect();
         }
 
@@ -86,13 +143,26 @@ impl BlockDevice for Virtual {
     }
 
     fn write(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()> {
-        if self.print_operations {
-            println!("write(offset={offset} bytes={})", bytes.len());
+        debug!(target: "overmask::operations", "write(offset={offset} bytes={})", bytes.len());
+        self.writes += 1;
+        self.bytes_written += bytes.len() as u64;
+        let lock = Arc::clone(&self.lock);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // the last block of an unaligned device is partial, anything past its end is discarded
+        let bytes = &bytes[..bounded_len(self.size, offset, bytes.len())];
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        if bytes.iter().all(|&byte| byte == 0) {
+            self.zero_writes += 1;
+            return self.write_zeroes(offset, bytes.len());
         }
 
         if let Err(error) = self.files.overlay.write_all_at(bytes, offset) {
-            eprintln!(
-                "overmask: couldn't write {} bytes to overlay file at offset {offset}: {error}",
+            error!(
+                "couldn't write {} bytes to overlay file at offset {offset}: {error}",
                 bytes.len(),
             );
             if !self.files.ignore_errors {
@@ -104,8 +174,8 @@ impl BlockDevice for Virtual {
             .mask
             .write_all_at(&vec![MASK; bytes.len()], offset)
         {
-            eprintln!(
-                "overmask: couldn't write {} bytes to mask file at offset {offset}: {error}",
+            error!(
+                "couldn't write {} bytes to mask file at offset {offset}: {error}",
                 bytes.len(),
             );
             if !self.files.ignore_errors {
@@ -116,18 +186,16 @@ impl BlockDevice for Virtual {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if self.print_operations {
-            println!("flush()");
-        }
+        debug!(target: "overmask::operations", "flush()");
 
-        if let Err(error) = self.files.overlay.flush() {
-            eprintln!("overmask: couldn't flush overlay file: {error}");
+        if let Err(error) = self.files.overlay.sync_data() {
+            error!("couldn't flush overlay file: {error}");
             if !self.files.ignore_errors {
                 return Err(error);
             }
         }
-        if let Err(error) = self.files.mask.flush() {
-            eprintln!("overmask: couldn't flush mask file: {error}");
+        if let Err(error) = self.files.mask.sync_data() {
+            error!("couldn't flush mask file: {error}");
             if !self.files.ignore_errors {
                 return Err(error);
             }
@@ -136,30 +204,24 @@ impl BlockDevice for Virtual {
     }
 
     fn trim(&mut self, offset: u64, len: u32) -> io::Result<()> {
-        if self.print_operations {
-            println!("trim(offset={offset} len={len})");
-        }
+        debug!(target: "overmask::operations", "trim(offset={offset} len={len})");
+        self.trims += 1;
+        let lock = Arc::clone(&self.lock);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
 
+        if self.hydrating {
+            // going back to the seed would undo hydration
+            return self.write_zeroes(offset, bounded_len(self.size, offset, len as usize));
+        }
         if !self.trim_no_punch_holes {
-            let flags = FallocateFlags::FALLOC_FL_KEEP_SIZE | FallocateFlags::FALLOC_FL_PUNCH_HOLE;
-            if let Err(error) = fallocate(
-                &self.files.mask,
-                flags,
-                offset.try_into().unwrap(),
-                len.into(),
-            ) {
-                eprintln!(
-                    "overmask: couldn't punch hole of size {len} in mask file at offset {offset}: {error}"
+            if let Err(error) = self.files.mask.punch_hole(offset, len.into()) {
+                error!(
+                    "couldn't punch hole of size {len} in mask file at offset {offset}: {error}"
                 );
             }
-            if let Err(error) = fallocate(
-                &self.files.overlay,
-                flags,
-                offset.try_into().unwrap(),
-                len.into(),
-            ) {
-                eprintln!(
-                    "overmask: couldn't punch hole of size {len} in overlay file at offset {offset}: {error}"
+            if let Err(error) = self.files.overlay.punch_hole(offset, len.into()) {
+                error!(
+                    "couldn't punch hole of size {len} in overlay file at offset {offset}: {error}"
                 );
             }
         }
@@ -167,9 +229,7 @@ impl BlockDevice for Virtual {
     }
 
     fn unmount(&mut self) {
-        if self.print_operations {
-            println!("unmount()");
-        }
+        debug!(target: "overmask::operations", "unmount()");
     }
 
     fn block_size(&self) -> u32 {
@@ -177,16 +237,55 @@ impl BlockDevice for Virtual {
     }
 
     fn blocks(&self) -> u64 {
-        self.files.seed_size / u64::from(self.files.block_size)
+        self.size.div_ceil(u64::from(self.files.block_size))
+    }
+}
+
+/// Store the seed data of every unmasked run in the overlay (marking zeroes as zeroed), returning
+/// the number of bytes copied
+pub fn copy_seed(
+    files: &Files,
+    seed_buffer: &[u8],
+    mask_buffer: &[u8],
+    offset: u64,
+) -> io::Result<u64> {
+    let len = bounded_len(files.seed_size, offset, seed_buffer.len());
+    let mut copied = 0;
+    let mut start = 0;
+    while start < len {
+        if mask_buffer[start] != 0 {
+            start += 1;
+            continue;
+        }
+        let end = mask_buffer[start..len]
+            .iter()
+            .position(|&byte| byte != 0)
+            .map_or(len, |run| start + run);
+        let run_offset = offset + start as u64;
+        let data = &seed_buffer[start..end];
+
+        if data.iter().all(|&byte| byte == 0) {
+            files
+                .mask
+                .write_all_at(&vec![ZEROED; data.len()], run_offset)?;
+        } else {
+            files.overlay.write_all_at(data, run_offset)?;
+            files
+                .mask
+                .write_all_at(&vec![MASK; data.len()], run_offset)?;
+        }
+        copied += data.len() as u64;
+        start = end;
     }
+    Ok(copied)
 }
 
-pub fn get_size(path: &PathBuf) -> u64 {
+pub fn get_size(path: &Path) -> u64 {
     if block_utils::is_block_device(path).unwrap_or(false) {
         match block_utils::get_device_info(path) {
             Ok(device_info) => device_info.capacity,
             Err(error) => {
-                eprintln!("overmask: couldn't query block device: {error}");
+                error!("couldn't query block device: {error}");
                 exit(1)
             }
         }
@@ -195,12 +294,12 @@ pub fn get_size(path: &PathBuf) -> u64 {
             Ok(file) => match file.metadata() {
                 Ok(metadata) => metadata.len(),
                 Err(error) => {
-                    eprintln!("overmask: couldn't query file metadata: {error}");
+                    error!("couldn't query file metadata: {error}");
                     exit(1)
                 }
             },
             Err(error) => {
-                eprintln!("overmask: couldn't open file: {error}");
+                error!("couldn't open file: {error}");
                 exit(1)
             }
         }
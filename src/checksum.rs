@@ -0,0 +1,129 @@
+use std::{
+    fs, io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+const ENTRY_SIZE: u64 = 8;
+
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+pub fn hash(bytes: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, bytes)
+}
+
+/// Per-block checksums of the seed, stored as one little-endian u64 per block (0 = not recorded)
+pub struct Checksums {
+    file: fs::File,
+    block_size: u32,
+    record: bool,
+
+    pub recorded: AtomicU64,
+    pub verified: AtomicU64,
+}
+
+impl Checksums {
+    pub fn open(path: &Path, block_size: u32, record: bool) -> io::Result<Self> {
+        Ok(Self {
+            file: fs::File::options()
+                .read(true)
+                .write(true)
+                .create(record)
+                .truncate(false)
+                .open(path)?,
+            block_size,
+            record,
+            recorded: AtomicU64::new(0),
+            verified: AtomicU64::new(0),
+        })
+    }
+
+    pub fn get(&self, block: u64) -> io::Result<Option<u64>> {
+        let mut entry = [0; 8];
+        let read = self.file.read_at(&mut entry, block * ENTRY_SIZE)?;
+        if read < entry.len() {
+            return Ok(None);
+        }
+        Ok(Some(u64::from_le_bytes(entry)).filter(|&checksum| checksum != 0))
+    }
+
+    pub fn set(&self, block: u64, data: &[u8]) -> io::Result<()> {
+        self.file
+            .write_all_at(&entry_for(data).to_le_bytes(), block * ENTRY_SIZE)?;
+        self.recorded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replace the checksum of a block that was intentionally modified (e.g. by apply)
+    pub fn update(&self, block: u64, data: &[u8]) -> io::Result<()> {
+        if self.record || self.get(block)?.is_some() {
+            self.set(block, data)?;
+        }
+        Ok(())
+    }
+
+    /// Verify (or record, if enabled) every whole block of seed data in `data` read from `offset`
+    pub fn check(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let block_size = u64::from(self.block_size);
+        let skip = offset.next_multiple_of(block_size) - offset;
+        let Some(aligned) = usize::try_from(skip).ok().and_then(|skip| data.get(skip..)) else {
+            return Ok(());
+        };
+
+        for (i, chunk) in aligned.chunks_exact(self.block_size as usize).enumerate() {
+            let block = (offset + skip) / block_size + i as u64;
+            match self.get(block)? {
+                Some(checksum) if checksum != entry_for(chunk) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "seed block {block} (offset {}) changed since its checksum was recorded",
+                            block * block_size
+                        ),
+                    ));
+                }
+                Some(_) => {
+                    self.verified.fetch_add(1, Ordering::Relaxed);
+                }
+                None if self.record => self.set(block, chunk)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of blocks that have a recorded checksum
+    pub fn count(&self) -> io::Result<u64> {
+        let mut buffer = vec![0; 32768];
+        let mut count = 0;
+        let mut offset = 0;
+        loop {
+            let read = self.file.read_at(&mut buffer, offset)?;
+            if read == 0 {
+                return Ok(count);
+            }
+            count += buffer[..read]
+                .chunks_exact(8)
+                .filter(|entry| entry.iter().any(|&byte| byte != 0))
+                .count() as u64;
+            offset += read as u64;
+        }
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+fn entry_for(data: &[u8]) -> u64 {
+    hash(data).max(1)
+}
@@ -0,0 +1,149 @@
+use crate::storage::{FileStorage, Storage};
+use std::{fs, io, os::unix::fs::FileExt, path::Path};
+
+const MAGIC: &[u8; 8] = b"OVERMASK";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 4096;
+
+/// Layout of a single-file session: a header block followed by the mask and overlay regions
+pub struct Header {
+    pub capacity: u64,
+    pub mask_offset: u64,
+    pub overlay_offset: u64,
+}
+
+impl Header {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            mask_offset: HEADER_SIZE,
+            overlay_offset: HEADER_SIZE + capacity.next_multiple_of(HEADER_SIZE),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(40);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&self.capacity.to_le_bytes());
+        bytes.extend_from_slice(&self.mask_offset.to_le_bytes());
+        bytes.extend_from_slice(&self.overlay_offset.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; 40]) -> io::Result<Self> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+
+        if &bytes[..8] != MAGIC {
+            return Err(invalid("not an overmask container"));
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid("unsupported container version"));
+        }
+        let header = Self {
+            capacity: u64_at(16),
+            mask_offset: u64_at(24),
+            overlay_offset: u64_at(32),
+        };
+        let (Some(mask_end), Some(_)) = (
+            header.mask_offset.checked_add(header.capacity),
+            header.overlay_offset.checked_add(header.capacity),
+        ) else {
+            return Err(invalid("container regions end past the largest file size"));
+        };
+        if header.mask_offset < HEADER_SIZE || header.overlay_offset < mask_end {
+            return Err(invalid("container regions overlap"));
+        }
+        Ok(header)
+    }
+}
+
+pub fn create(path: &Path, capacity: u64) -> io::Result<()> {
+    let file = fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let header = Header::new(capacity);
+    file.write_all_at(&header.to_bytes(), 0)?;
+    file.set_len(header.overlay_offset + capacity)
+}
+
+/// Open a container, returning its overlay and mask regions
+pub fn open(path: &Path) -> io::Result<(Box<dyn Storage>, Box<dyn Storage>)> {
+    let file = fs::File::options().read(true).write(true).open(path)?;
+    let mut bytes = [0; 40];
+    file.read_exact_at(&mut bytes, 0)?;
+    let header = Header::from_bytes(&bytes)?;
+
+    Ok((
+        Box::new(FileStorage::region(
+            file.try_clone()?,
+            header.overlay_offset,
+            header.capacity,
+        )),
+        Box::new(FileStorage::region(
+            file,
+            header.mask_offset,
+            header.capacity,
+        )),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> io::Result<Header> {
+        Header::from_bytes(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header::new(1_000_001);
+        let decoded = decode(&header.to_bytes()).unwrap();
+        assert_eq!(decoded.capacity, 1_000_001);
+        assert_eq!(decoded.mask_offset, HEADER_SIZE);
+        assert_eq!(decoded.overlay_offset, header.overlay_offset);
+        assert!(decoded.overlay_offset.is_multiple_of(HEADER_SIZE));
+        assert!(decoded.overlay_offset >= decoded.mask_offset + decoded.capacity);
+    }
+
+    #[test]
+    fn header_rejects_invalid_bytes() {
+        let bytes = Header::new(4096).to_bytes();
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(
+            decode(&magic).err().unwrap().to_string(),
+            "not an overmask container"
+        );
+
+        let mut version = bytes.clone();
+        version[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            decode(&version).err().unwrap().to_string(),
+            "unsupported container version"
+        );
+
+        let mut overlapping = bytes.clone();
+        overlapping[32..40].copy_from_slice(&(HEADER_SIZE + 100).to_le_bytes());
+        assert_eq!(
+            decode(&overlapping).err().unwrap().to_string(),
+            "container regions overlap"
+        );
+
+        let mut overflowing = bytes;
+        overflowing[16..24].copy_from_slice(&(u64::MAX - 100).to_le_bytes());
+        let error = decode(&overflowing).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "container regions end past the largest file size"
+        );
+    }
+}
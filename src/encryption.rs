@@ -0,0 +1,175 @@
+use crate::{arguments::Arguments, storage::Cipher, with_suffix};
+use argon2::Argon2;
+use chacha20poly1305::aead::Generate;
+use log::{error, info};
+use std::{env, fmt::Write, fs, io, path::Path, process::exit};
+
+const PASSPHRASE_VARIABLE: &str = "OVERMASK_PASSPHRASE";
+
+/// What's needed to re-derive the key of an encrypted session (never the key itself)
+struct Parameters {
+    salt: [u8; 16],
+    block_size: u32,
+    check: Vec<u8>,
+}
+
+impl Parameters {
+    fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let invalid = |key| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {key}"));
+
+        let (mut salt, mut block_size, mut check) = (None, None, None);
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("salt", value)) => {
+                    salt = from_hex(value).and_then(|salt| salt.try_into().ok());
+                }
+                Some(("block_size", value)) => block_size = value.parse().ok(),
+                Some(("check", value)) => check = from_hex(value),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            salt: salt.ok_or_else(|| invalid("salt"))?,
+            block_size: block_size.ok_or_else(|| invalid("block_size"))?,
+            check: check.ok_or_else(|| invalid("check"))?,
+        })
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let contents = format!(
+            "salt={}\nblock_size={}\ncheck={}\n",
+            to_hex(&self.salt),
+            self.block_size,
+            to_hex(&self.check)
+        );
+
+        let temporary_file = with_suffix(path, ".tmp");
+        fs::write(&temporary_file, contents)?;
+        fs::rename(&temporary_file, path)
+    }
+}
+
+/// Derive the session cipher from `--key-file` or `--passphrase`, if the session is encrypted
+pub fn open(arguments: &Arguments, parameters_file: &Path, init: bool) -> Option<Cipher> {
+    let secret = read_secret(arguments, init);
+    match (secret, parameters_file.exists()) {
+        (None, false) => None,
+        (None, true) => {
+            error!("session is encrypted, specify --key-file or --passphrase");
+            exit(1);
+        }
+        (Some(_), false) if !init => {
+            error!(
+                "session isn't encrypted (no encryption parameters at {}), encryption can only be enabled by init",
+                parameters_file.to_string_lossy()
+            );
+            exit(1);
+        }
+        (Some(secret), false) => {
+            let salt = <[u8; 16]>::generate();
+            let cipher = derive(&secret, &salt, arguments.block_size);
+            let parameters = Parameters {
+                salt,
+                block_size: arguments.block_size,
+                check: check_value(&cipher),
+            };
+            if let Err(error) = parameters.write(parameters_file) {
+                error!("couldn't write encryption parameters: {error}");
+                exit(1);
+            }
+            info!(
+                "created encryption parameters at {}",
+                parameters_file.to_string_lossy()
+            );
+            Some(cipher)
+        }
+        (Some(secret), true) => {
+            let parameters = match Parameters::read(parameters_file) {
+                Ok(parameters) => parameters,
+                Err(error) => {
+                    error!("couldn't read encryption parameters: {error}");
+                    exit(1);
+                }
+            };
+            let cipher = derive(&secret, &parameters.salt, parameters.block_size);
+            if check_value(&cipher) != parameters.check {
+                error!("wrong key or passphrase for this session");
+                exit(1);
+            }
+            Some(cipher)
+        }
+    }
+}
+
+fn read_secret(arguments: &Arguments, init: bool) -> Option<Vec<u8>> {
+    if let Some(key_file) = &arguments.key_file {
+        return match fs::read(key_file) {
+            Ok(key) if key.is_empty() => {
+                error!("key file {} is empty", key_file.to_string_lossy());
+                exit(1);
+            }
+            Ok(key) => Some(key),
+            Err(error) => {
+                error!("couldn't read key file: {error}");
+                exit(1);
+            }
+        };
+    }
+    if !arguments.passphrase {
+        return None;
+    }
+    if let Ok(passphrase) = env::var(PASSPHRASE_VARIABLE) {
+        return Some(passphrase.into_bytes());
+    }
+
+    let prompt = |prompt| match rpassword::prompt_password(prompt) {
+        Ok(passphrase) => passphrase,
+        Err(error) => {
+            error!("couldn't read passphrase (or set {PASSPHRASE_VARIABLE}): {error}");
+            exit(1);
+        }
+    };
+    let passphrase = prompt("passphrase: ");
+    if init && prompt("confirm passphrase: ") != passphrase {
+        error!("passphrases don't match");
+        exit(1);
+    }
+    Some(passphrase.into_bytes())
+}
+
+fn derive(secret: &[u8], salt: &[u8], block_size: u32) -> Cipher {
+    let mut key = [0; 32];
+    if let Err(error) = Argon2::default().hash_password_into(secret, salt, &mut key) {
+        error!("couldn't derive encryption key: {error}");
+        exit(1);
+    }
+    Cipher::new(&key, block_size)
+}
+
+fn check_value(cipher: &Cipher) -> Vec<u8> {
+    match cipher.check_value() {
+        Ok(check) => check,
+        Err(error) => {
+            error!("couldn't compute key check value: {error}");
+            exit(1);
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
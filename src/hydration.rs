@@ -0,0 +1,120 @@
+use crate::{Files, block_device::copy_seed, bounded_len, interrupt, progress::Progress};
+use log::{error, info, warn};
+use std::{
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Background task copying every unmasked seed region into the overlay, so the seed is no longer
+/// needed once it's done
+pub struct Hydration {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Hydration {
+    /// Start hydrating, reading at most `rate` bytes per second from the seed (0 for no limit)
+    pub fn start(files: Arc<Files>, lock: Arc<Mutex<()>>, rate: u64) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || hydrate(&files, &lock, rate, &stop)
+        });
+        Self { stop, thread }
+    }
+
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if self.thread.join().is_err() {
+            error!("hydration thread panicked");
+        }
+    }
+}
+
+fn hydrate(files: &Files, lock: &Mutex<()>, rate: u64, stop: &AtomicBool) {
+    info!("hydrating overlay from seed in the background...");
+
+    let mut seed_buffer = vec![0; files.block_size as usize];
+    let mut mask_buffer = vec![0; files.block_size as usize];
+    let mut bytes_copied = 0;
+    let mut bytes_read = 0;
+    let mut blocks_failed = 0;
+
+    let start = Instant::now();
+    let block_limit = files.seed_blocks();
+    let mut progress = Progress::new(files.progress, "hydrating", block_limit, files.block_size);
+    for block in 0..block_limit {
+        progress.update(block);
+        let offset = block * u64::from(files.block_size);
+
+        if stop.load(Ordering::SeqCst) || interrupt::interrupted() {
+            progress.interrupted(block);
+            info!("stopped hydrating at offset {offset} after copying {bytes_copied} bytes");
+            return;
+        }
+
+        let guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        mask_buffer.fill(0);
+        if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
+                files.block_size
+            );
+            blocks_failed += 1;
+            continue;
+        }
+        let len = bounded_len(files.seed_size, offset, mask_buffer.len());
+        if !mask_buffer[..len].contains(&0) {
+            continue;
+        }
+
+        seed_buffer.fill(0);
+        if let Err(error) = files.seed.read_at(&mut seed_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from seed file at offset {offset}: {error}",
+                files.block_size
+            );
+            blocks_failed += 1;
+            continue;
+        }
+        if let Some(checksums) = &files.checksums
+            && let Err(error) = checksums.check(offset, &seed_buffer)
+        {
+            error!("{error}");
+            blocks_failed += 1;
+            continue;
+        }
+        match copy_seed(files, &seed_buffer, &mask_buffer, offset) {
+            Ok(copied) => bytes_copied += copied,
+            Err(error) => {
+                error!("couldn't copy seed data at offset {offset} to overlay: {error}");
+                blocks_failed += 1;
+            }
+        }
+        drop(guard);
+
+        bytes_read += len as u64;
+        if rate > 0 {
+            #[allow(clippy::cast_precision_loss)]
+            let due = Duration::from_secs_f64(bytes_read as f64 / rate as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+    progress.finish();
+
+    if blocks_failed > 0 {
+        warn!(
+            "copied {bytes_copied} bytes to overlay, but {blocks_failed} blocks couldn't be hydrated and still need the seed"
+        );
+    } else {
+        info!(
+            "session fully hydrated after copying {bytes_copied} bytes, the seed is no longer needed"
+        );
+    }
+}
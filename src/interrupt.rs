@@ -0,0 +1,31 @@
+use log::error;
+use std::sync::{
+    OnceLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static CALLBACK: OnceLock<Box<dyn Fn() + Send + Sync>> = OnceLock::new();
+
+pub const EXIT_CODE: i32 = 130;
+
+pub fn install() {
+    if let Err(error) = ctrlc::set_handler(|| {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        if let Some(callback) = CALLBACK.get() {
+            callback();
+        }
+    }) {
+        error!("couldn't add ctrlc handler: {error}");
+    }
+}
+
+pub fn on_interrupt(callback: impl Fn() + Send + Sync + 'static) {
+    if CALLBACK.set(Box::new(callback)).is_err() {
+        error!("interrupt callback was already set");
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -1,64 +1,495 @@
 mod arguments;
 mod block_device;
+mod checksum;
+mod container;
+mod encryption;
+mod hydration;
+mod interrupt;
+mod manifest;
 mod modes;
+mod progress;
+mod seed;
+mod storage;
+#[cfg(test)]
+mod temp_path;
 
-use crate::arguments::{Arguments, MainSubcommand};
+use crate::arguments::{Arguments, MainSubcommand, ProgressMode};
 use crate::block_device::get_size;
+use crate::checksum::Checksums;
+use crate::seed::{RetryPolicy, Seed};
+use crate::storage::{Cipher, Compressed, Deduplicated, FileStorage, Storage};
 use clap::Parser;
-use std::{fs, process::exit};
+use log::{Level, LevelFilter, error, info};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
 
+/// Mask byte for data stored in the overlay (0 means the seed)
 const MASK: u8 = 0xff;
+/// Mask byte for data that was overwritten with zeroes, which takes no overlay space
+const ZEROED: u8 = 0x80;
 
 pub struct Files {
-    pub seed: fs::File,
+    pub seed: Seed,
     pub seed_size: u64,
 
-    pub overlay: fs::File,
+    pub overlay: Box<dyn Storage>,
     pub overlay_size: u64,
 
-    pub mask: fs::File,
+    pub mask: Box<dyn Storage>,
     pub mask_size: u64,
 
     pub block_size: u32,
     pub ignore_errors: bool,
+    pub progress: ProgressMode,
+    pub checksums: Option<Checksums>,
+    pub cipher: Option<Cipher>,
+    pub layers: Layers,
 }
 
-fn main() {
-    let arguments = Arguments::parse();
+/// Optional overlay layers, each kept in files next to the session (mask or container file path)
+#[derive(Clone, Copy)]
+pub struct Layers {
+    pub compressed: bool,
+    pub deduplicated: bool,
+}
 
-    let seed = match fs::File::open(&arguments.seed_file) {
-        Ok(file) => file,
-        Err(error) => {
-            eprintln!("overmask: couldn't open seed file: {error}");
-            exit(1);
+impl Layers {
+    const INDEX_SUFFIX: &str = ".index";
+    const MAP_SUFFIX: &str = ".dedup";
+    const BLOCKS_SUFFIX: &str = ".dedup-blocks";
+
+    fn detect(session_file: &Path) -> Self {
+        Self {
+            compressed: with_suffix(session_file, Self::INDEX_SUFFIX).exists(),
+            deduplicated: with_suffix(session_file, Self::MAP_SUFFIX).exists(),
+        }
+    }
+
+    /// Wrap the overlay in every enabled layer (deduplicating on top of compressing)
+    fn open(
+        self,
+        mut overlay: Box<dyn Storage>,
+        session_file: &Path,
+        block_size: u32,
+        create: bool,
+    ) -> Box<dyn Storage> {
+        if self.compressed {
+            let index_file = with_suffix(session_file, Self::INDEX_SUFFIX);
+            overlay = match Compressed::open(overlay, &index_file, block_size.max(4096), create) {
+                Ok(compressed) => Box::new(compressed),
+                Err(error) => {
+                    error!("couldn't open compression index: {error}");
+                    exit(1);
+                }
+            };
+        }
+        if self.deduplicated {
+            overlay = match Deduplicated::open(
+                overlay,
+                &with_suffix(session_file, Self::MAP_SUFFIX),
+                &with_suffix(session_file, Self::BLOCKS_SUFFIX),
+                block_size,
+                create,
+            ) {
+                Ok(deduplicated) => Box::new(deduplicated),
+                Err(error) => {
+                    error!("couldn't open deduplication map: {error}");
+                    exit(1);
+                }
+            };
+        }
+        overlay
+    }
+
+    fn files(session_file: &Path) -> [(&'static str, PathBuf); 3] {
+        [
+            ("index", with_suffix(session_file, Self::INDEX_SUFFIX)),
+            (
+                "deduplication map",
+                with_suffix(session_file, Self::MAP_SUFFIX),
+            ),
+            (
+                "deduplication block",
+                with_suffix(session_file, Self::BLOCKS_SUFFIX),
+            ),
+        ]
+    }
+}
+
+impl Files {
+    /// Number of blocks covering the seed, including a final partial block
+    pub fn seed_blocks(&self) -> u64 {
+        self.seed_size.div_ceil(u64::from(self.block_size))
+    }
+
+    pub fn sync(&self) {
+        if let Err(error) = self.overlay.sync_all() {
+            error!("couldn't sync overlay file: {error}");
         }
+        if let Err(error) = self.mask.sync_all() {
+            error!("couldn't sync mask file: {error}");
+        }
+        if let Some(checksums) = &self.checksums
+            && let Err(error) = checksums.sync()
+        {
+            error!("couldn't sync checksum file: {error}");
+        }
+    }
+}
+
+/// How many of `len` bytes starting at `offset` lie before `end`
+#[allow(clippy::cast_possible_truncation)]
+fn bounded_len(end: u64, offset: u64, len: usize) -> usize {
+    end.saturating_sub(offset).min(len as u64) as usize
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn sidecar_file(path: Option<&PathBuf>, session_file: &Path, suffix: &str) -> PathBuf {
+    path.cloned()
+        .unwrap_or_else(|| with_suffix(session_file, suffix))
+}
+
+fn init_logger(arguments: &Arguments) {
+    let level = match (arguments.quiet, arguments.verbose) {
+        (0, 0) => LevelFilter::Info,
+        (1, _) => LevelFilter::Warn,
+        (_, 0) => LevelFilter::Error,
+        (_, 1) => LevelFilter::Debug,
+        (_, _) => LevelFilter::Trace,
     };
-    let overlay = match fs::File::options()
-        .read(true)
-        .write(true)
-        .open(&arguments.overlay_file)
+
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(level)
+        .format(|formatter, record| match record.level() {
+            Level::Info => writeln!(formatter, "{}", record.args()),
+            level => writeln!(
+                formatter,
+                "overmask: {}: {}",
+                level.as_str().to_lowercase(),
+                record.args()
+            ),
+        });
+    if let MainSubcommand::Device {
+        print_operations: true,
+        ..
+    } = arguments.subcommand
     {
-        Ok(file) => file,
+        builder.filter_module("overmask::operations", LevelFilter::Debug);
+    }
+    builder.parse_env("RUST_LOG").init();
+}
+
+/// File kept next to the session: what it is, where it is and the suffix it's named with by default
+type Sidecar<'a> = (&'a str, &'a Path, &'a str);
+
+/// Layers enabled by init (after validating the new session's files) or found next to the session
+fn select_layers(arguments: &Arguments, session_file: &Path, sidecar_files: &[Sidecar]) -> Layers {
+    let MainSubcommand::Init {
+        compress,
+        deduplicate,
+        ..
+    } = arguments.subcommand
+    else {
+        return Layers::detect(session_file);
+    };
+
+    let layer_files = Layers::files(session_file);
+    let mut sidecar_files: Vec<(&str, &Path)> = sidecar_files
+        .iter()
+        .map(|&(name, path, _)| (name, path))
+        .collect();
+    sidecar_files.extend(
+        layer_files
+            .iter()
+            .map(|(name, path)| (*name, path.as_path())),
+    );
+    modes::init::validate(arguments, &sidecar_files);
+    Layers {
+        compressed: compress,
+        deduplicated: deduplicate,
+    }
+}
+
+fn open_seed(arguments: &Arguments, bad_block_file: &Path, http_cache_file: &Path) -> Seed {
+    let retry_policy = RetryPolicy {
+        retries: arguments.read_retries,
+        delay: Duration::from_millis(arguments.retry_delay),
+    };
+    match Seed::open(
+        &arguments.seed_file,
+        http_cache_file,
+        &arguments.seed_mirror,
+        bad_block_file,
+        retry_policy,
+        arguments.fail_unreadable,
+    )
+    .and_then(|seed| seed.with_window(arguments.seed_offset, arguments.seed_length))
+    {
+        Ok(seed) if arguments.seed_cache > 0 => {
+            seed.with_cache(arguments.seed_cache, arguments.read_ahead)
+        }
+        Ok(seed) => seed,
         Err(error) => {
-            eprintln!("overmask: couldn't open overlay file: {error}");
+            error!("couldn't open seed file: {error}");
             exit(1);
         }
-    };
-    let mask = match fs::File::options()
-        .read(true)
-        .write(true)
-        .open(&arguments.mask_file)
-    {
-        Ok(file) => file,
+    }
+}
+
+fn open_session(
+    arguments: &Arguments,
+    init: bool,
+    seed_size: u64,
+    cipher: Option<&Cipher>,
+) -> (Box<dyn Storage>, u64, Box<dyn Storage>, u64) {
+    if let Some(container_file) = &arguments.container_file {
+        let capacity = cipher.map_or(seed_size, |cipher| cipher.physical_len(seed_size));
+        if init && let Err(error) = container::create(container_file, capacity) {
+            error!("couldn't create container file: {error}");
+            exit(1);
+        }
+        return match container::open(container_file)
+            .and_then(|(overlay, mask)| storage::with_cipher(overlay, mask, cipher, init))
+        {
+            Ok((overlay, mask)) => {
+                let capacity = overlay.len().unwrap_or(0);
+                (overlay, capacity, mask, capacity)
+            }
+            Err(error) => {
+                error!("couldn't open container file: {error}");
+                exit(1);
+            }
+        };
+    }
+
+    let mut session = Vec::with_capacity(2);
+    for (name, path) in [
+        ("overlay", arguments.overlay_file.as_ref()),
+        ("mask", arguments.mask_file.as_ref()),
+    ] {
+        let path = path.expect("clap requires overlay and mask files without a container");
+        match fs::File::options()
+            .read(true)
+            .write(true)
+            .create(init)
+            .truncate(false)
+            .open(path)
+        {
+            Ok(file) => {
+                let size = get_size(path);
+                session.push((
+                    Box::new(FileStorage::file(file)) as Box<dyn Storage>,
+                    cipher.map_or(size, |cipher| cipher.logical_len(size)),
+                ));
+            }
+            Err(error) => {
+                error!("couldn't open {name} file: {error}");
+                exit(1);
+            }
+        }
+    }
+    let (mask, mask_size) = session.pop().unwrap();
+    let (overlay, overlay_size) = session.pop().unwrap();
+    let (overlay, mask) = storage::with_cipher(overlay, mask, cipher, false)
+        .expect("encrypted session files don't need formatting");
+    (overlay, overlay_size, mask, mask_size)
+}
+
+/// Exit if a layer would leak information about the overlay from outside the encryption
+fn refuse_unencrypted_layers(layers: Layers) {
+    if layers.deduplicated {
+        error!(
+            "deduplication can't be combined with encryption, block hashes would reveal overlay contents"
+        );
+        exit(1);
+    }
+    if layers.compressed {
+        error!(
+            "compression can't be combined with encryption, the compressed size of each block would reveal overlay contents"
+        );
+        exit(1);
+    }
+}
+
+fn open_checksums(arguments: &Arguments, checksum_file: &Path) -> Option<Checksums> {
+    let record_checksums = arguments.checksums
+        || matches!(
+            arguments.subcommand,
+            MainSubcommand::Init { hash: true, .. }
+        );
+    if record_checksums || checksum_file.exists() {
+        match Checksums::open(checksum_file, arguments.block_size, record_checksums) {
+            Ok(checksums) => Some(checksums),
+            Err(error) => {
+                error!("couldn't open checksum file: {error}");
+                exit(1);
+            }
+        }
+    } else {
+        None
+    }
+}
+
+fn run(
+    arguments: Arguments,
+    mut files: Files,
+    session_file: &Path,
+    manifest_file: &Path,
+    sidecar_files: &[Sidecar],
+) {
+    match arguments.subcommand {
+        MainSubcommand::Apply {
+            force,
+            resume,
+            checkpoint_file,
+        } => {
+            modes::apply::main(
+                &files,
+                &arguments.seed_file,
+                force,
+                resume,
+                &checkpoint_file.unwrap_or_else(|| with_suffix(session_file, ".checkpoint")),
+                manifest_file,
+            );
+            // applying data written past the end of the seed grows it
+            files.seed.clear_cache();
+            files.seed_size = files.seed.len().unwrap_or(files.seed_size);
+            manifest::refresh(manifest_file, &arguments.seed_file, &files);
+        }
+        MainSubcommand::Clean { truncate } => modes::clean::main(&files, truncate),
+        MainSubcommand::Gc => modes::gc::main(&files),
+        MainSubcommand::Init {
+            preallocate,
+            sparse,
+            hash,
+            compress: _,
+            deduplicate: _,
+        } => modes::init::main(&files, preallocate, sparse, hash),
+        MainSubcommand::Info => modes::info::main(&files),
+        MainSubcommand::Convert {
+            to_container_file,
+            to_overlay_file,
+            to_mask_file,
+        } => modes::convert::main(
+            &files,
+            &sidecar_files
+                .iter()
+                .map(|&(_, path, suffix)| (path, suffix))
+                .collect::<Vec<_>>(),
+            to_container_file.as_deref(),
+            to_overlay_file.as_deref().zip(to_mask_file.as_deref()),
+        ),
+        MainSubcommand::Device {
+            nbd_device,
+            nbd_timeout,
+            print_operations: _,
+            trim_no_punch_holes,
+            size,
+            copy_on_read,
+            hydrate,
+            hydrate_rate,
+        } => {
+            let size = size.unwrap_or(files.seed_size);
+            if arguments.container_file.is_some() && size > files.mask_size {
+                error!(
+                    "device size ({size} bytes) is larger than the container's capacity ({} bytes)",
+                    files.mask_size
+                );
+                exit(1);
+            }
+            modes::device::main(
+                files,
+                &nbd_device,
+                nbd_timeout,
+                trim_no_punch_holes,
+                size,
+                copy_on_read,
+                hydrate.then_some(hydrate_rate),
+            );
+        }
+    }
+}
+
+fn main() {
+    let arguments = Arguments::parse();
+    init_logger(&arguments);
+
+    let session_file = arguments
+        .container_file
+        .as_ref()
+        .or(arguments.mask_file.as_ref())
+        .expect("clap requires a mask or container file")
+        .clone();
+    let manifest_file = sidecar_file(arguments.manifest_file.as_ref(), &session_file, ".manifest");
+    let checksum_file = sidecar_file(
+        arguments.checksum_file.as_ref(),
+        &session_file,
+        ".checksums",
+    );
+    let encryption_file = sidecar_file(
+        arguments.encryption_file.as_ref(),
+        &session_file,
+        ".encryption",
+    );
+    let bad_block_file = sidecar_file(
+        arguments.bad_block_file.as_ref(),
+        &session_file,
+        ".badblocks",
+    );
+    let http_cache_file = sidecar_file(
+        arguments.http_cache_file.as_ref(),
+        &session_file,
+        ".http-cache",
+    );
+    let http_cache_map_file = with_suffix(&http_cache_file, ".map");
+    // validated by init and copied by convert
+    let sidecar_files = [
+        ("manifest", manifest_file.as_path(), ".manifest"),
+        ("checksum", checksum_file.as_path(), ".checksums"),
+        ("encryption", encryption_file.as_path(), ".encryption"),
+        ("bad-block map", bad_block_file.as_path(), ".badblocks"),
+        ("HTTP cache", http_cache_file.as_path(), ".http-cache"),
+        (
+            "HTTP cache map",
+            http_cache_map_file.as_path(),
+            ".http-cache.map",
+        ),
+    ];
+    let init = matches!(arguments.subcommand, MainSubcommand::Init { .. });
+    let layers = select_layers(&arguments, &session_file, &sidecar_files);
+
+    let seed = open_seed(&arguments, &bad_block_file, &http_cache_file);
+    let seed_size = match seed.len() {
+        Ok(seed_size) => seed_size,
         Err(error) => {
-            eprintln!("overmask: couldn't open mask file: {error}");
+            error!("couldn't query seed size: {error}");
             exit(1);
         }
     };
-    let seed_size = get_size(&arguments.seed_file);
-    let overlay_size = get_size(&arguments.overlay_file);
-    let mask_size = get_size(&arguments.mask_file);
-    println!("seed: {seed_size} bytes, overlay: {overlay_size} bytes, mask: {mask_size} bytes");
+    if arguments.key_file.is_some() || arguments.passphrase {
+        refuse_unencrypted_layers(layers);
+    }
+    let cipher = encryption::open(&arguments, &encryption_file, init);
+    let (mut overlay, mut overlay_size, mask, mask_size) =
+        open_session(&arguments, init, seed_size, cipher.as_ref());
+    if layers.compressed || layers.deduplicated {
+        overlay = layers.open(overlay, &session_file, arguments.block_size, init);
+        overlay_size = overlay.len().unwrap_or(0);
+    }
+    info!("seed: {seed_size} bytes, overlay: {overlay_size} bytes, mask: {mask_size} bytes");
+
+    let checksums = open_checksums(&arguments, &checksum_file);
 
     let files = Files {
         seed,
@@ -69,21 +500,24 @@ fn main() {
         mask_size,
         block_size: arguments.block_size,
         ignore_errors: arguments.ignore_errors,
+        progress: arguments.progress,
+        checksums,
+        cipher,
+        layers,
     };
-    match arguments.subcommand {
-        MainSubcommand::Apply { force } => modes::apply::main(&files, &arguments.seed_file, force),
-        MainSubcommand::Clean { truncate } => modes::clean::main(&files, truncate),
-        MainSubcommand::Device {
-            nbd_device,
-            nbd_timeout,
-            print_operations,
-            trim_no_punch_holes,
-        } => modes::device::main(
-            files,
-            &nbd_device,
-            nbd_timeout,
-            print_operations,
-            trim_no_punch_holes,
-        ),
-    }
+    manifest::verify(
+        &manifest_file,
+        &arguments.seed_file,
+        &files,
+        arguments.ignore_manifest,
+    );
+
+    interrupt::install();
+    run(
+        arguments,
+        files,
+        &session_file,
+        &manifest_file,
+        &sidecar_files,
+    );
 }
@@ -0,0 +1,262 @@
+use crate::{Files, checksum, with_suffix};
+use log::{error, info, warn};
+use std::{
+    fs, io,
+    path::Path,
+    process::exit,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SAMPLES: u64 = 64;
+const SAMPLE_SIZE: usize = 4096;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub seed_size: u64,
+    pub seed_offset: u64,
+    pub block_size: u32,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub sample_hash: Option<u64>,
+    pub created: u64,
+    /// An apply was started and hasn't finished, so the seed size and data may have changed
+    pub applying: bool,
+}
+
+impl Manifest {
+    pub fn generate(seed_file: &Path, files: &Files) -> Self {
+        let (serial, wwn) = if block_utils::is_block_device(seed_file).unwrap_or(false) {
+            (
+                block_utils::get_device_info(seed_file)
+                    .ok()
+                    .and_then(|device_info| device_info.serial_number),
+                block_utils::get_block_dev_property(seed_file, "ID_WWN")
+                    .ok()
+                    .flatten(),
+            )
+        } else {
+            (None, None)
+        };
+
+        Self {
+            seed_size: files.seed_size,
+            seed_offset: files.seed.window().0,
+            block_size: files.block_size,
+            serial,
+            wwn,
+            sample_hash: match sample_hash(files) {
+                Ok(hash) => Some(hash),
+                Err(error) => {
+                    warn!("couldn't sample seed for manifest: {error}");
+                    None
+                }
+            },
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            applying: false,
+        }
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let invalid = |key| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {key}"));
+
+        let mut manifest = Self {
+            seed_size: 0,
+            seed_offset: 0,
+            block_size: 0,
+            serial: None,
+            wwn: None,
+            sample_hash: None,
+            created: 0,
+            applying: false,
+        };
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("seed_size", value)) => {
+                    manifest.seed_size = value.parse().map_err(|_| invalid("seed_size"))?;
+                }
+                Some(("seed_offset", value)) => {
+                    manifest.seed_offset = value.parse().map_err(|_| invalid("seed_offset"))?;
+                }
+                Some(("block_size", value)) => {
+                    manifest.block_size = value.parse().map_err(|_| invalid("block_size"))?;
+                }
+                Some(("serial", value)) => manifest.serial = Some(value.to_string()),
+                Some(("wwn", value)) => manifest.wwn = Some(value.to_string()),
+                Some(("sample_hash", value)) => {
+                    manifest.sample_hash =
+                        Some(u64::from_str_radix(value, 16).map_err(|_| invalid("sample_hash"))?);
+                }
+                Some(("created", value)) => {
+                    manifest.created = value.parse().map_err(|_| invalid("created"))?;
+                }
+                Some(("applying", value)) => manifest.applying = value == "1",
+                _ => {}
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut lines = vec![
+            format!("seed_size={}", self.seed_size),
+            format!("block_size={}", self.block_size),
+        ];
+        // only written for windowed seeds, so older manifests (without it) still match
+        if self.seed_offset != 0 {
+            lines.push(format!("seed_offset={}", self.seed_offset));
+        }
+        if let Some(serial) = &self.serial {
+            lines.push(format!("serial={serial}"));
+        }
+        if let Some(wwn) = &self.wwn {
+            lines.push(format!("wwn={wwn}"));
+        }
+        if let Some(sample_hash) = self.sample_hash {
+            lines.push(format!("sample_hash={sample_hash:016x}"));
+        }
+        lines.push(format!("created={}", self.created));
+        if self.applying {
+            lines.push("applying=1".to_string());
+        }
+        let contents = lines.join("\n") + "\n";
+
+        let temporary_file = with_suffix(path, ".tmp");
+        fs::write(&temporary_file, contents)?;
+        fs::rename(&temporary_file, path)
+    }
+
+    pub fn mismatches(&self, current: &Self) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if !self.applying && self.seed_size != current.seed_size {
+            mismatches.push(format!(
+                "seed size is {} bytes, but the session was created for {} bytes",
+                current.seed_size, self.seed_size
+            ));
+        }
+        if self.seed_offset != current.seed_offset {
+            mismatches.push(format!(
+                "seed window starts at offset {}, but the session was created for offset {}",
+                current.seed_offset, self.seed_offset
+            ));
+        }
+        if self.block_size != current.block_size {
+            mismatches.push(format!(
+                "block size is {} bytes, but the session was created with {} bytes",
+                current.block_size, self.block_size
+            ));
+        }
+        if self.serial.is_some() && self.serial != current.serial {
+            mismatches.push(format!(
+                "seed serial is {}, but the session was created for {}",
+                current.serial.as_deref().unwrap_or("unknown"),
+                self.serial.as_deref().unwrap_or("unknown")
+            ));
+        }
+        if self.wwn.is_some() && self.wwn != current.wwn {
+            mismatches.push(format!(
+                "seed WWN is {}, but the session was created for {}",
+                current.wwn.as_deref().unwrap_or("unknown"),
+                self.wwn.as_deref().unwrap_or("unknown")
+            ));
+        }
+        if !self.applying && self.sample_hash.is_some() && self.sample_hash != current.sample_hash {
+            mismatches.push("sampled seed data doesn't match the session".to_string());
+        }
+        mismatches
+    }
+}
+
+pub fn verify(manifest_file: &Path, seed_file: &Path, files: &Files, ignore_manifest: bool) {
+    let current = Manifest::generate(seed_file, files);
+    if !manifest_file.exists() {
+        match current.write(manifest_file) {
+            Ok(()) => info!(
+                "created session manifest at {}",
+                manifest_file.to_string_lossy()
+            ),
+            Err(error) => error!("couldn't write manifest file: {error}"),
+        }
+        return;
+    }
+
+    let manifest = match Manifest::read(manifest_file) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            error!("couldn't read manifest file: {error}");
+            if !ignore_manifest {
+                exit(1);
+            }
+            return;
+        }
+    };
+    if manifest.applying {
+        warn!(
+            "an apply of this session didn't finish, so the seed size and data aren't checked against the manifest (resume it with apply --resume)"
+        );
+    }
+    let mismatches = manifest.mismatches(&current);
+    if mismatches.is_empty() {
+        return;
+    }
+
+    for mismatch in &mismatches {
+        if ignore_manifest {
+            warn!("{mismatch}");
+        } else {
+            error!("{mismatch}");
+        }
+    }
+    if !ignore_manifest {
+        error!(
+            "seed doesn't match session manifest at {}, specify --ignore-manifest to continue anyway",
+            manifest_file.to_string_lossy()
+        );
+        exit(1);
+    }
+}
+
+/// Mark the manifest as being applied, so the seed changing under an unfinished apply isn't
+/// mistaken for a different seed
+pub fn mark_applying(manifest_file: &Path) {
+    let mut manifest = match Manifest::read(manifest_file) {
+        Ok(manifest) => manifest,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return,
+        Err(error) => {
+            error!("couldn't read manifest file: {error}");
+            return;
+        }
+    };
+    manifest.applying = true;
+    if let Err(error) = manifest.write(manifest_file) {
+        error!("couldn't update manifest file: {error}");
+    }
+}
+
+pub fn refresh(manifest_file: &Path, seed_file: &Path, files: &Files) {
+    let mut manifest = Manifest::generate(seed_file, files);
+    if let Ok(existing) = Manifest::read(manifest_file) {
+        manifest.created = existing.created;
+    }
+    if let Err(error) = manifest.write(manifest_file) {
+        error!("couldn't update manifest file: {error}");
+    }
+}
+
+fn sample_hash(files: &Files) -> io::Result<u64> {
+    let mut buffer = vec![0; SAMPLE_SIZE];
+    let mut hash = checksum::hash(&[]);
+
+    let sample_size = SAMPLE_SIZE as u64;
+    let stride = files.seed_size / SAMPLES;
+    for sample in 0..SAMPLES {
+        buffer.fill(0);
+        files
+            .seed
+            .read_at(&mut buffer, sample * stride / sample_size * sample_size)?;
+        hash = checksum::fnv1a(hash, &buffer);
+    }
+    Ok(hash)
+}
@@ -1,51 +1,109 @@
-use crate::{Files, MASK};
-use std::{fs, os::unix::fs::FileExt, path::PathBuf, process::exit};
+use crate::{
+    Files, MASK, ZEROED, bounded_len, checksum::Checksums, interrupt, manifest, progress::Progress,
+    with_suffix,
+};
+use log::{error, info, warn};
+use std::{
+    fs, io,
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    process::exit,
+    time::{Duration, Instant},
+};
 
-pub fn main(files: &Files, seed_file: &PathBuf, force: bool) {
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn main(
+    files: &Files,
+    seed_file: &PathBuf,
+    force: bool,
+    resume: bool,
+    checkpoint_file: &Path,
+    manifest_file: &Path,
+) {
     if !force {
-        println!("This is the only mode that will write data to your seed file.");
-        println!("If you are sure you want to do this, specify the --force flag.");
+        warn!("This is the only mode that will write data to your seed file.");
+        warn!("If you are sure you want to do this, specify the --force flag.");
         exit(2);
     }
+    check_writeable(files);
+    let end = end(files, seed_file);
+
+    let fingerprint = fingerprint(files);
+    // saved with checkpoints, since applying data past the end of the seed grows it
+    let (first_block, seed_size) = first_block(files, resume, checkpoint_file, &fingerprint);
+    if let Some(checksums) = &files.checksums {
+        verify_checksums(files, checksums);
+    }
 
     let writeable_seed = match fs::File::options().read(true).write(true).open(seed_file) {
         Ok(file) => file,
         Err(error) => {
-            eprintln!("overmask: couldn't open seed file: {error}");
+            error!("couldn't open seed file: {error}");
             exit(1);
         }
     };
+    // cleared by refreshing the manifest once the apply finishes
+    manifest::mark_applying(manifest_file);
     let mut overlay_buffer = vec![0; files.block_size as usize];
     let mut mask_buffer = vec![0; files.block_size as usize];
-    let mut blocks_applied = 0;
+    let mut blocks_applied: u64 = 0;
+    let mut bytes_applied = 0;
 
-    let mut last_percent = 0.0;
-    let block_limit = files.mask_size / u64::from(files.block_size);
-    for block in 0..block_limit {
-        #[allow(clippy::cast_precision_loss)]
-        let percent = block as f64 / block_limit as f64 * 100.0;
-        if percent - last_percent > 0.1 {
-            last_percent = percent;
-            println!("applying blocks: {percent:.1}% ({block}/{block_limit})");
-        }
+    let mut last_checkpoint = Instant::now();
+    let block_limit = end.div_ceil(u64::from(files.block_size));
+    let mut progress = Progress::new(files.progress, "applying", block_limit, files.block_size);
+    for block in first_block..block_limit {
+        progress.update(block);
         let offset = block * u64::from(files.block_size);
 
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            save_checkpoint(
+                &writeable_seed,
+                checkpoint_file,
+                offset,
+                &fingerprint,
+                seed_size,
+            );
+            info!(
+                "interrupted at offset {offset} after processing {} blocks: applied {blocks_applied} blocks ({bytes_applied} bytes), saved checkpoint",
+                block - first_block
+            );
+            exit(interrupt::EXIT_CODE);
+        }
+        if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            save_checkpoint(
+                &writeable_seed,
+                checkpoint_file,
+                offset,
+                &fingerprint,
+                seed_size,
+            );
+            last_checkpoint = Instant::now();
+        }
+
+        mask_buffer.fill(0);
         if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
-            eprintln!(
-                "overmask: couldn't read {} bytes from mask file at offset {offset}: {error}",
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
                 files.block_size,
             );
             if !files.ignore_errors {
                 exit(1);
             }
         }
+        mask_buffer[bounded_len(end, offset, files.block_size as usize)..].fill(0);
         if mask_buffer.iter().all(|&byte| byte == 0) {
             continue;
         }
 
-        if let Err(error) = files.overlay.read_at(&mut overlay_buffer, offset) {
-            eprintln!(
-                "overmask: couldn't read {} bytes from overlay file at offset {offset}: {error}",
+        overlay_buffer.fill(0);
+        if mask_buffer.contains(&MASK)
+            && let Err(error) = files.overlay.read_at(&mut overlay_buffer, offset)
+        {
+            error!(
+                "couldn't read {} bytes from overlay file at offset {offset}: {error}",
                 files.block_size,
             );
             if !files.ignore_errors {
@@ -53,46 +111,295 @@ pub fn main(files: &Files, seed_file: &PathBuf, force: bool) {
             }
         }
 
-        let mut buffer = Vec::with_capacity(files.block_size as usize);
-        let mut possible_start = None;
-        for (i, (mask, overlay)) in mask_buffer.iter().zip(&overlay_buffer).enumerate() {
-            if mask == &MASK {
-                if possible_start.is_none() {
-                    possible_start = Some(i);
-                }
-                buffer.push(*overlay);
-            } else if let Some(start) = possible_start {
-                if let Err(error) = writeable_seed.write_all_at(&buffer, offset + start as u64) {
-                    eprintln!(
-                        "overmask: couldn't write {} bytes to seed file at offset {}: {error}",
-                        buffer.len(),
-                        offset + start as u64
-                    );
-                    if !files.ignore_errors {
-                        exit(1)
-                    }
-                }
-                buffer.clear();
-                possible_start = None;
+        bytes_applied += apply_block(
+            files,
+            &writeable_seed,
+            &mask_buffer,
+            &overlay_buffer,
+            offset,
+        );
+        if let Some(checksums) = &files.checksums {
+            update_checksum(files, checksums, &writeable_seed, block);
+        }
+
+        blocks_applied += 1;
+    }
+    progress.finish();
+    info!("successfully applied {blocks_applied} blocks ({bytes_applied} bytes) to seed");
+
+    if let Err(error) = fs::remove_file(checkpoint_file)
+        && error.kind() != io::ErrorKind::NotFound
+    {
+        error!("couldn't remove checkpoint file: {error}");
+    }
+}
+
+/// Exit if the seed isn't stored as-is in a single file that can be written to in place
+fn check_writeable(files: &Files) {
+    if files.seed.compression().is_some() {
+        error!("can't apply to a compressed seed, decompress it first");
+        exit(1);
+    }
+    if files.seed.segments() > 1 {
+        error!("can't apply to a split seed, join its segments first");
+        exit(1);
+    }
+}
+
+/// End of the data to apply, exiting if changes past it would be lost (unless errors are ignored)
+///
+/// A seed file grows to hold data written past its end (device --size), a block device or a
+/// window of limited length can't.
+fn end(files: &Files, seed_file: &Path) -> u64 {
+    if !block_utils::is_block_device(seed_file).unwrap_or(false) && files.seed.window().1.is_none()
+    {
+        return files.mask_size;
+    }
+    let end = files.mask_size.min(files.seed_size);
+    if end == files.mask_size {
+        return end;
+    }
+
+    let mut mask_buffer = vec![0; files.block_size as usize];
+    let mut blocks_lost: u64 = 0;
+    for block in
+        end / u64::from(files.block_size)..files.mask_size.div_ceil(u64::from(files.block_size))
+    {
+        let offset = block * u64::from(files.block_size);
+        mask_buffer.fill(0);
+        if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
+                files.block_size,
+            );
+            exit(1);
+        }
+        let past_end = usize::try_from(end.saturating_sub(offset)).unwrap_or(usize::MAX);
+        if mask_buffer.iter().skip(past_end).any(|&byte| byte != 0) {
+            blocks_lost += 1;
+        }
+    }
+    if blocks_lost > 0 {
+        error!(
+            "{blocks_lost} modified blocks lie past the end of the seed at {end} bytes and can't be applied"
+        );
+        if !files.ignore_errors {
+            exit(1);
+        }
+        warn!("applying the rest, the {blocks_lost} blocks past the end of the seed are lost");
+    }
+    end
+}
+
+/// Write every masked run of a block to the seed, returning the number of bytes written
+fn apply_block(
+    files: &Files,
+    writeable_seed: &fs::File,
+    mask_buffer: &[u8],
+    overlay_buffer: &[u8],
+    offset: u64,
+) -> u64 {
+    let mut buffer = Vec::with_capacity(files.block_size as usize);
+    let mut bytes_applied = 0;
+    let mut possible_start = None;
+    for (i, (mask, overlay)) in mask_buffer.iter().zip(overlay_buffer).enumerate() {
+        if mask == &MASK || mask == &ZEROED {
+            if possible_start.is_none() {
+                possible_start = Some(i);
             }
+            buffer.push(if mask == &MASK { *overlay } else { 0 });
+            bytes_applied += 1;
+        } else if let Some(start) = possible_start {
+            write_seed(files, writeable_seed, &buffer, offset + start as u64);
+            buffer.clear();
+            possible_start = None;
         }
-        if let Some(start) = possible_start
-            && let Err(error) = writeable_seed.write_all_at(&buffer, offset + start as u64)
-        {
-            eprintln!(
-                "overmask: couldn't write {} bytes to seed file at offset {}: {error}",
-                buffer.len(),
-                offset + start as u64
+    }
+    if let Some(start) = possible_start {
+        write_seed(files, writeable_seed, &buffer, offset + start as u64);
+    }
+    bytes_applied
+}
+
+fn write_seed(files: &Files, writeable_seed: &fs::File, buffer: &[u8], offset: u64) {
+    if let Err(error) = writeable_seed.write_all_at(buffer, files.seed.window().0 + offset) {
+        error!(
+            "couldn't write {} bytes to seed file at offset {offset}: {error}",
+            buffer.len(),
+        );
+        if !files.ignore_errors {
+            exit(1)
+        }
+    }
+}
+
+fn verify_checksums(files: &Files, checksums: &Checksums) {
+    info!("verifying seed block checksums...");
+
+    let mut seed_buffer = vec![0; files.block_size as usize];
+    let mut mismatches = 0;
+
+    let block_limit = files.seed_blocks();
+    let mut progress = Progress::new(files.progress, "verifying", block_limit, files.block_size);
+    for block in 0..block_limit {
+        progress.update(block);
+        let offset = block * u64::from(files.block_size);
+
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            info!("interrupted at offset {offset} while verifying checksums, nothing was applied");
+            exit(interrupt::EXIT_CODE);
+        }
+
+        match checksums.get(block) {
+            Ok(Some(_)) => {}
+            Ok(None) => continue,
+            Err(error) => {
+                error!("couldn't read checksum of seed block {block}: {error}");
+                exit(1);
+            }
+        }
+        seed_buffer.fill(0);
+        if let Err(error) = files.seed.read_at(&mut seed_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from seed file at offset {offset}: {error}",
+                files.block_size,
             );
-            if !files.ignore_errors {
-                exit(1)
+            exit(1);
+        }
+        if let Err(error) = checksums.check(offset, &seed_buffer) {
+            error!("{error}");
+            mismatches += 1;
+        }
+    }
+    progress.finish();
+
+    if mismatches > 0 {
+        error!(
+            "{mismatches} seed blocks changed since their checksums were recorded, refusing to apply"
+        );
+        exit(1);
+    }
+}
+
+fn update_checksum(files: &Files, checksums: &Checksums, writeable_seed: &fs::File, block: u64) {
+    let mut seed_buffer = vec![0; files.block_size as usize];
+    if let Err(error) = writeable_seed
+        .read_at(
+            &mut seed_buffer,
+            files.seed.window().0 + block * u64::from(files.block_size),
+        )
+        .and_then(|_| checksums.update(block, &seed_buffer))
+    {
+        error!("couldn't update checksum of seed block {block}: {error}");
+    }
+}
+
+/// Block to start applying from and the size the seed had before the apply started
+fn first_block(
+    files: &Files,
+    resume: bool,
+    checkpoint_file: &Path,
+    fingerprint: &str,
+) -> (u64, u64) {
+    if resume {
+        match read_checkpoint(checkpoint_file) {
+            // the seed may have grown up to the mask size before the apply was interrupted
+            Ok((offset, saved_fingerprint, seed_size))
+                if saved_fingerprint == fingerprint
+                    && (seed_size..=seed_size.max(files.mask_size)).contains(&files.seed_size) =>
+            {
+                info!("resuming from checkpoint at offset {offset}");
+                (offset / u64::from(files.block_size), seed_size)
+            }
+            Ok(_) => {
+                error!(
+                    "checkpoint file {} doesn't match the current seed, overlay and mask",
+                    checkpoint_file.to_string_lossy()
+                );
+                exit(1);
             }
+            Err(error) => {
+                error!("couldn't read checkpoint file: {error}");
+                exit(1);
+            }
+        }
+    } else {
+        if checkpoint_file.exists() {
+            info!(
+                "found checkpoint file at {}, specify --resume to continue from it",
+                checkpoint_file.to_string_lossy()
+            );
         }
+        (0, files.seed_size)
+    }
+}
 
-        blocks_applied += 1;
+fn fingerprint(files: &Files) -> String {
+    let mut fingerprint = vec![
+        files.overlay_size.to_string(),
+        files.mask_size.to_string(),
+        files.block_size.to_string(),
+    ];
+    for file in [&files.overlay, &files.mask] {
+        match file.metadata() {
+            Ok(metadata) => fingerprint.push(format!(
+                "{}.{}.{}.{}",
+                metadata.dev(),
+                metadata.ino(),
+                metadata.mtime(),
+                metadata.mtime_nsec()
+            )),
+            Err(error) => {
+                error!("couldn't query file metadata: {error}");
+                exit(1);
+            }
+        }
+    }
+    fingerprint.join(":")
+}
+
+fn read_checkpoint(checkpoint_file: &Path) -> io::Result<(u64, String, u64)> {
+    let contents = fs::read_to_string(checkpoint_file)?;
+    let mut offset = None;
+    let mut fingerprint = None;
+    let mut seed_size = None;
+    for line in contents.lines() {
+        match line.split_once('=') {
+            Some(("offset", value)) => offset = value.parse().ok(),
+            Some(("fingerprint", value)) => fingerprint = Some(value.to_string()),
+            Some(("seed_size", value)) => seed_size = value.parse().ok(),
+            _ => {}
+        }
+    }
+    match (offset, fingerprint, seed_size) {
+        (Some(offset), Some(fingerprint), Some(seed_size)) => Ok((offset, fingerprint, seed_size)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing offset, fingerprint or seed size",
+        )),
+    }
+}
+
+fn save_checkpoint(
+    writeable_seed: &fs::File,
+    checkpoint_file: &Path,
+    offset: u64,
+    fingerprint: &str,
+    seed_size: u64,
+) {
+    if let Err(error) = writeable_seed.sync_data() {
+        error!("couldn't sync seed file, not saving checkpoint: {error}");
+        return;
+    }
+
+    let temporary_file = with_suffix(checkpoint_file, ".tmp");
+    if let Err(error) = fs::write(
+        &temporary_file,
+        format!("offset={offset}\nfingerprint={fingerprint}\nseed_size={seed_size}\n"),
+    )
+    .and_then(|()| fs::rename(&temporary_file, checkpoint_file))
+    {
+        error!("couldn't save checkpoint file: {error}");
     }
-    println!(
-        "successfully applied {blocks_applied} blocks ({} bytes) to seed",
-        blocks_applied * files.block_size
-    );
 }
@@ -1,96 +1,146 @@
-use crate::Files;
-use std::{os::unix::fs::FileExt, process::exit};
+use crate::{Files, MASK, ZEROED, interrupt, progress::Progress};
+use log::{error, info};
+use std::process::exit;
 
 pub fn main(files: &Files, truncate: bool) {
-    println!("deduplicating seed and overlay files...");
+    info!("deduplicating seed and overlay files...");
 
     let mut seed_buffer = vec![0; files.block_size as usize];
     let mut overlay_buffer = vec![0; files.block_size as usize];
-    let zeros = vec![0; files.block_size as usize];
+    let mut mask_buffer = vec![0; files.block_size as usize];
+    let mut bytes_cleared = 0;
     let mut blocks_freed = 0;
 
-    let mut last_percent = 0.0;
-    let block_limit = files.seed_size / u64::from(files.block_size);
+    let block_limit = files
+        .seed_size
+        .min(files.mask_size)
+        .div_ceil(u64::from(files.block_size));
+    let mut progress = Progress::new(files.progress, "comparing", block_limit, files.block_size);
     for block in 0..block_limit {
-        #[allow(clippy::cast_precision_loss)]
-        let percent = block as f64 / block_limit as f64 * 100.0;
-        if percent - last_percent > 0.1 {
-            last_percent = percent;
-            println!("comparing blocks: {percent:.1}% ({block}/{block_limit})");
-        }
+        progress.update(block);
         let offset = block * u64::from(files.block_size);
 
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            files.sync();
+            info!(
+                "interrupted at offset {offset} after processing {block} blocks: cleared {bytes_cleared} bytes ({blocks_freed} blocks fully freed)"
+            );
+            exit(interrupt::EXIT_CODE);
+        }
+
+        mask_buffer.fill(0);
+        if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
+                files.block_size
+            );
+            if !files.ignore_errors {
+                exit(1);
+            }
+        }
+        if mask_buffer.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+
+        overlay_buffer.fill(0);
         if let Err(error) = files.overlay.read_at(&mut overlay_buffer, offset) {
-            eprintln!(
-                "overmask: couldn't read {} bytes from overlay file at offset {offset}: {error}",
+            error!(
+                "couldn't read {} bytes from overlay file at offset {offset}: {error}",
                 files.block_size
             );
             if !files.ignore_errors {
                 exit(1);
             }
         }
+        seed_buffer.fill(0);
         if let Err(error) = files.seed.read_at(&mut seed_buffer, offset) {
-            eprintln!(
-                "overmask: couldn't read {} bytes from seed file at offset {offset}: {error}",
+            error!(
+                "couldn't read {} bytes from seed file at offset {offset}: {error}",
                 files.block_size
             );
             if !files.ignore_errors {
                 exit(1);
             }
+            // unreadable sectors are zero-filled, comparing them could clear real changes
+            continue;
+        }
+        if let Some(checksums) = &files.checksums
+            && let Err(error) = checksums.check(offset, &seed_buffer)
+        {
+            error!("{error}");
+            exit(1);
         }
 
-        if seed_buffer == overlay_buffer {
-            if let Err(error) = files.mask.write_all_at(&zeros, offset) {
-                eprintln!(
-                    "overmask: couldn't write {} bytes to mask file at offset {offset}: {error}",
-                    files.block_size
-                );
-                if !files.ignore_errors {
-                    exit(1);
-                }
-            }
-            if let Err(error) = files.overlay.write_all_at(&zeros, offset) {
-                eprintln!(
-                    "overmask: couldn't write {} bytes to overlay file at offset {offset}: {error}",
-                    files.block_size
-                );
-                if !files.ignore_errors {
-                    exit(1);
+        let mut possible_start = None;
+        for i in 0..=mask_buffer.len() {
+            let redundant = i < mask_buffer.len()
+                && match mask_buffer[i] {
+                    MASK => overlay_buffer[i] == seed_buffer[i],
+                    ZEROED => seed_buffer[i] == 0,
+                    _ => false,
+                };
+            if redundant {
+                if possible_start.is_none() {
+                    possible_start = Some(i);
                 }
+            } else if let Some(start) = possible_start {
+                clear_range(files, offset + start as u64, (i - start) as u64);
+                mask_buffer[start..i].fill(0);
+                bytes_cleared += i - start;
+                possible_start = None;
             }
+        }
+        if mask_buffer.iter().all(|&byte| byte == 0) {
             blocks_freed += 1;
         }
     }
-    println!(
-        "successfully zeroed {blocks_freed} blocks ({} bytes)",
-        blocks_freed * files.block_size
-    );
+    progress.finish();
+    info!("successfully cleared {bytes_cleared} bytes ({blocks_freed} blocks fully freed)");
+    files.seed.report_cache();
 
     if truncate {
         do_truncate(files);
     }
 }
 
-fn do_truncate(files: &Files) {
-    println!("locating end of mask file...");
+fn clear_range(files: &Files, offset: u64, len: u64) {
+    if let Err(error) = files.mask.punch_hole(offset, len) {
+        error!("couldn't punch hole of size {len} in mask file at offset {offset}: {error}");
+        if !files.ignore_errors {
+            exit(1);
+        }
+    }
+    if let Err(error) = files.overlay.punch_hole(offset, len) {
+        error!("couldn't punch hole of size {len} in overlay file at offset {offset}: {error}");
+        if !files.ignore_errors {
+            exit(1);
+        }
+    }
+}
+
+pub fn do_truncate(files: &Files) {
+    info!("locating end of mask file...");
 
     let mut mask_buffer = vec![0; files.block_size as usize];
     let mut end_of_file = None;
 
-    let mut last_percent = 0.0;
-    let block_limit = files.mask_size / u64::from(files.block_size);
+    let block_limit = files.mask_size.div_ceil(u64::from(files.block_size));
+    let mut progress = Progress::new(files.progress, "checking", block_limit, files.block_size);
     for block in (0..block_limit).rev() {
-        #[allow(clippy::cast_precision_loss)]
-        let percent = 100.0 - block as f64 / block_limit as f64 * 100.0;
-        if percent - last_percent > 0.1 {
-            last_percent = percent;
-            println!("checking blocks: {percent:.1}% ({block}/{block_limit})");
-        }
+        progress.update(block_limit - block);
         let offset = block * u64::from(files.block_size);
 
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            info!("interrupted at offset {offset} while locating end of mask file, not truncating");
+            exit(interrupt::EXIT_CODE);
+        }
+
+        mask_buffer.fill(0);
         if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
-            eprintln!(
-                "overmask: couldn't read {} bytes from mask file at offset {offset}: {error}",
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
                 files.block_size
             );
             if !files.ignore_errors {
@@ -102,18 +152,19 @@ fn do_truncate(files: &Files) {
         }
         end_of_file = Some(offset);
     }
+    progress.finish();
     if let Some(offset) = end_of_file {
-        println!("truncating overlay and mask files to {offset} bytes...");
+        info!("truncating overlay and mask files to {offset} bytes...");
         if let Err(error) = files.mask.set_len(offset) {
-            eprintln!("overmask: couldn't truncate mask file to {offset} bytes: {error}");
+            error!("couldn't truncate mask file to {offset} bytes: {error}");
             exit(1);
         }
         if let Err(error) = files.overlay.set_len(offset) {
-            eprintln!("overmask: couldn't truncate overlay file to {offset} bytes: {error}");
+            error!("couldn't truncate overlay file to {offset} bytes: {error}");
             exit(1);
         }
-        println!("successfully truncated overlay and mask files to {offset} bytes");
+        info!("successfully truncated overlay and mask files to {offset} bytes");
     } else {
-        println!("no unused block found");
+        info!("no unused block found");
     }
 }
@@ -0,0 +1,177 @@
+use crate::{
+    Files, Layers, container, interrupt,
+    progress::Progress,
+    storage::{self, FileStorage, Storage},
+    with_suffix,
+};
+use log::{error, info};
+use std::{fs, path::Path, process::exit};
+
+pub fn main(
+    files: &Files,
+    sidecar_files: &[(&Path, &str)],
+    to_container_file: Option<&Path>,
+    to_files: Option<(&Path, &Path)>,
+) {
+    let (overlay, mask, session_file) = create_target(files, to_container_file, to_files);
+    info!(
+        "copying overlay and mask to {}...",
+        session_file.to_string_lossy()
+    );
+
+    let mut overlay_buffer = vec![0; files.block_size as usize];
+    let mut mask_buffer = vec![0; files.block_size as usize];
+    let mut blocks_copied = 0;
+
+    let block_limit = files.mask_size.div_ceil(u64::from(files.block_size));
+    let mut progress = Progress::new(files.progress, "copying", block_limit, files.block_size);
+    for block in 0..block_limit {
+        progress.update(block);
+        let offset = block * u64::from(files.block_size);
+
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            error!(
+                "interrupted at offset {offset} after copying {blocks_copied} blocks, {} is incomplete",
+                session_file.to_string_lossy()
+            );
+            exit(interrupt::EXIT_CODE);
+        }
+
+        mask_buffer.fill(0);
+        if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
+                files.block_size
+            );
+            if !files.ignore_errors {
+                exit(1);
+            }
+        }
+        if mask_buffer.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+
+        overlay_buffer.fill(0);
+        if let Err(error) = files.overlay.read_at(&mut overlay_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from overlay file at offset {offset}: {error}",
+                files.block_size
+            );
+            if !files.ignore_errors {
+                exit(1);
+            }
+        }
+
+        let len = usize::try_from(files.mask_size - offset)
+            .map_or(mask_buffer.len(), |left| left.min(mask_buffer.len()));
+        for (name, storage, buffer) in [
+            ("overlay", &overlay, &overlay_buffer),
+            ("mask", &mask, &mask_buffer),
+        ] {
+            if let Err(error) = storage.write_all_at(&buffer[..len], offset) {
+                error!("couldn't write {len} bytes to new {name} at offset {offset}: {error}");
+                exit(1);
+            }
+        }
+        blocks_copied += 1;
+    }
+    progress.finish();
+
+    for (name, storage) in [("overlay", &overlay), ("mask", &mask)] {
+        if let Err(error) = storage
+            .set_len(files.mask_size)
+            .and_then(|()| storage.sync_all())
+        {
+            error!("couldn't sync new {name}: {error}");
+            exit(1);
+        }
+    }
+    for &(path, suffix) in sidecar_files {
+        if path.exists()
+            && let Err(error) = fs::copy(path, with_suffix(session_file, suffix))
+        {
+            error!("couldn't copy {} file: {error}", path.to_string_lossy());
+        }
+    }
+    info!(
+        "successfully copied {blocks_copied} blocks ({} bytes) to {}",
+        blocks_copied * files.block_size,
+        session_file.to_string_lossy()
+    );
+}
+
+fn create_target<'a>(
+    files: &Files,
+    to_container_file: Option<&'a Path>,
+    to_files: Option<(&'a Path, &'a Path)>,
+) -> (Box<dyn Storage>, Box<dyn Storage>, &'a Path) {
+    let (overlay, mask, session_file) = match (to_container_file, to_files) {
+        (Some(container_file), _) => {
+            refuse_existing("container", container_file);
+            let capacity = files.seed_size.max(files.mask_size);
+            let capacity = files
+                .cipher
+                .as_ref()
+                .map_or(capacity, |cipher| cipher.physical_len(capacity));
+            if let Err(error) = container::create(container_file, capacity) {
+                error!("couldn't create container file: {error}");
+                exit(1);
+            }
+            match container::open(container_file) {
+                Ok((overlay, mask)) => (overlay, mask, container_file),
+                Err(error) => {
+                    error!("couldn't open container file: {error}");
+                    exit(1);
+                }
+            }
+        }
+        (None, Some((overlay_file, mask_file))) => (
+            create_file("overlay", overlay_file),
+            create_file("mask", mask_file),
+            mask_file,
+        ),
+        (None, None) => unreachable!("clap requires a conversion target"),
+    };
+    // a new container starts out zeroed, which isn't a valid map of encrypted blocks
+    let (overlay, mask) = match storage::with_cipher(overlay, mask, files.cipher.as_ref(), true) {
+        Ok(storages) => storages,
+        Err(error) => {
+            error!("couldn't initialize encrypted conversion target: {error}");
+            exit(1);
+        }
+    };
+    for (name, path) in Layers::files(session_file) {
+        refuse_existing(name, &path);
+    }
+    let overlay = files
+        .layers
+        .open(overlay, session_file, files.block_size, true);
+    (overlay, mask, session_file)
+}
+
+fn refuse_existing(name: &str, path: &Path) {
+    if path.exists() {
+        error!(
+            "{name} file {} already exists, refusing to overwrite it",
+            path.to_string_lossy()
+        );
+        exit(1);
+    }
+}
+
+fn create_file(name: &str, path: &Path) -> Box<dyn Storage> {
+    refuse_existing(name, path);
+    match fs::File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(file) => Box::new(FileStorage::file(file)),
+        Err(error) => {
+            error!("couldn't create {name} file: {error}");
+            exit(1);
+        }
+    }
+}
@@ -1,43 +1,111 @@
-use crate::{Files, block_device::Virtual};
-use std::path::PathBuf;
+use crate::{Files, block_device::Virtual, hydration::Hydration, interrupt};
+use log::{error, info, warn};
+use std::{
+    path::PathBuf,
+    process::exit,
+    sync::{Arc, Mutex, atomic::Ordering},
+};
 use vblk::mount;
 
 pub fn main(
     files: Files,
     nbd_device: &PathBuf,
     nbd_timeout: u64,
-    print_operations: bool,
     trim_no_punch_holes: bool,
+    size: u64,
+    copy_on_read: bool,
+    hydrate_rate: Option<u64>,
 ) {
+    if size < files.seed_size {
+        error!(
+            "device size ({size} bytes) can't be smaller than the seed ({} bytes)",
+            files.seed_size
+        );
+        exit(1);
+    }
+    if size > files.seed_size {
+        info!(
+            "growing device to {size} bytes, the {} bytes past the end of the seed are stored in the overlay",
+            size - files.seed_size
+        );
+    }
+    let tail = size % u64::from(files.block_size);
+    if tail > 0 {
+        warn!(
+            "device size isn't a multiple of the block size, the last block has only {tail} bytes: the rest reads as zeroes and writes to it are discarded"
+        );
+    }
     let mut virtual_block_device = Virtual {
-        files,
-        print_operations,
+        files: Arc::new(files),
+        lock: Arc::new(Mutex::new(())),
         trim_no_punch_holes,
+        size,
+        copy_on_read,
+        hydrating: hydrate_rate.is_some(),
+        reads: 0,
+        writes: 0,
+        bytes_written: 0,
+        zero_writes: 0,
+        trims: 0,
+        bytes_copied: 0,
     };
+    let hydration = hydrate_rate.map(|rate| {
+        Hydration::start(
+            Arc::clone(&virtual_block_device.files),
+            Arc::clone(&virtual_block_device.lock),
+            rate,
+        )
+    });
     unsafe {
         if let Err(error) = mount(&mut virtual_block_device, nbd_device, |device| {
-            println!(
+            info!(
                 "successfully opened virtual block device at {}",
                 nbd_device.to_string_lossy()
             );
 
             if let Err(error) = device.set_timeout(std::time::Duration::from_secs(nbd_timeout)) {
-                eprintln!(
-                    "overmask: couldn't set virtual block device timeout to {nbd_timeout} seconds: {error}",
+                error!(
+                    "couldn't set virtual block device timeout to {nbd_timeout} seconds: {error}",
                 );
             }
 
-            if let Err(error) = ctrlc::set_handler(move || {
+            interrupt::on_interrupt(move || {
                 if let Err(error) = device.unmount() {
-                    eprintln!("overmask: couldn't unmount virtual block device: {error}");
+                    error!("couldn't unmount virtual block device: {error}");
                 }
-            }) {
-                eprintln!("overmask: couldn't add ctrlc handler: {error}");
-            }
+            });
 
             Ok(())
         }) {
-            eprintln!("overmask: couldn't mount virtual block device: {error}");
+            error!("couldn't mount virtual block device: {error}");
         }
     };
+
+    if let Some(hydration) = hydration {
+        hydration.stop();
+    }
+    virtual_block_device.files.sync();
+    virtual_block_device.files.seed.report_mirrors();
+    virtual_block_device.files.seed.report_cache();
+    info!(
+        "virtual block device stopped after {} reads, {} writes ({} bytes, {} of them zeroes) and {} trims",
+        virtual_block_device.reads,
+        virtual_block_device.writes,
+        virtual_block_device.bytes_written,
+        virtual_block_device.zero_writes,
+        virtual_block_device.trims
+    );
+    if copy_on_read {
+        info!(
+            "copied {} bytes of seed data to the overlay",
+            virtual_block_device.bytes_copied
+        );
+    }
+    if let Some(checksums) = &virtual_block_device.files.checksums {
+        info!(
+            "verified {} and recorded {} seed block checksums",
+            checksums.verified.load(Ordering::Relaxed),
+            checksums.recorded.load(Ordering::Relaxed)
+        );
+    }
 }
@@ -0,0 +1,79 @@
+use crate::{Files, MASK, interrupt, modes::clean::do_truncate, progress::Progress};
+use log::{error, info};
+use std::process::exit;
+
+pub fn main(files: &Files) {
+    info!("reclaiming unmasked overlay regions...");
+
+    let mut mask_buffer = vec![0; files.block_size as usize];
+    let mut bytes_reclaimed = 0;
+
+    let mut possible_start = None;
+    let block_limit = files.overlay_size.div_ceil(u64::from(files.block_size));
+    let mut progress = Progress::new(files.progress, "scanning", block_limit, files.block_size);
+    for block in 0..block_limit {
+        progress.update(block);
+        let offset = block * u64::from(files.block_size);
+
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            if let Some(start) = possible_start {
+                bytes_reclaimed += reclaim_range(files, start, offset - start);
+            }
+            files.sync();
+            info!(
+                "interrupted at offset {offset} after processing {block} blocks: deallocated {bytes_reclaimed} bytes"
+            );
+            exit(interrupt::EXIT_CODE);
+        }
+
+        mask_buffer.fill(0);
+        if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
+                files.block_size
+            );
+            if !files.ignore_errors {
+                exit(1);
+            }
+            // the block may well be masked, so the run of unmasked data ends before it
+            if let Some(start) = possible_start.take() {
+                bytes_reclaimed += reclaim_range(files, start, offset - start);
+            }
+            continue;
+        }
+
+        for (i, &mask) in mask_buffer.iter().enumerate() {
+            if mask != MASK {
+                if possible_start.is_none() {
+                    possible_start = Some(offset + i as u64);
+                }
+            } else if let Some(start) = possible_start {
+                bytes_reclaimed += reclaim_range(files, start, offset + i as u64 - start);
+                possible_start = None;
+            }
+        }
+    }
+    progress.finish();
+    if let Some(start) = possible_start {
+        bytes_reclaimed += reclaim_range(files, start, files.overlay_size.saturating_sub(start));
+    }
+    info!("successfully deallocated {bytes_reclaimed} bytes of unmasked overlay data");
+
+    do_truncate(files);
+}
+
+fn reclaim_range(files: &Files, offset: u64, len: u64) -> u64 {
+    if len == 0 {
+        return 0;
+    }
+
+    if let Err(error) = files.overlay.punch_hole(offset, len) {
+        error!("couldn't punch hole of size {len} in overlay file at offset {offset}: {error}");
+        if !files.ignore_errors {
+            exit(1);
+        }
+        return 0;
+    }
+    len
+}
@@ -0,0 +1,123 @@
+use crate::{Files, MASK, ZEROED, interrupt, progress::Progress};
+use log::{error, info};
+use std::process::exit;
+
+pub fn main(files: &Files) {
+    info!(
+        "block size: {} bytes ({} seed blocks)",
+        files.block_size,
+        files.seed_blocks()
+    );
+    if !files.seed_size.is_multiple_of(u64::from(files.block_size)) {
+        info!(
+            "last block: {} bytes of seed data, padded with zeroes",
+            files.seed_size % u64::from(files.block_size)
+        );
+    }
+    match files.seed.window() {
+        (0, None) => {}
+        (offset, _) => info!(
+            "seed window: {} bytes starting at offset {offset}",
+            files.seed_size
+        ),
+    }
+    if files.seed.segments() > 1 {
+        info!("seed segments: {}", files.seed.segments());
+    }
+    if let Some(compression) = files.seed.compression() {
+        info!("seed compression: {compression}");
+    }
+    info!(
+        "encryption: {}",
+        if files.cipher.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    match files.overlay.compression() {
+        Ok(Some((uncompressed, compressed))) => info!(
+            "compression: {uncompressed} bytes stored in {compressed} bytes ({})",
+            format_ratio(uncompressed, compressed)
+        ),
+        Ok(None) => info!("compression: disabled"),
+        Err(error) => error!("couldn't read compression index: {error}"),
+    }
+    match files.overlay.deduplication() {
+        Ok(Some((referenced, unique))) => info!(
+            "deduplication: {referenced} blocks stored as {unique} unique blocks ({})",
+            format_ratio(referenced, unique)
+        ),
+        Ok(None) => info!("deduplication: disabled"),
+        Err(error) => error!("couldn't read deduplication map: {error}"),
+    }
+    match files.seed.bad_sectors() {
+        0 => {}
+        bad_sectors => info!("bad-block map: {bad_sectors} unreadable seed sectors"),
+    }
+    if let Some(checksums) = &files.checksums {
+        match checksums.count() {
+            Ok(count) => info!("checksums: recorded for {count} seed blocks"),
+            Err(error) => error!("couldn't read checksum file: {error}"),
+        }
+    }
+
+    let (modified, zeroed) = count_modified(files);
+    #[allow(clippy::cast_precision_loss)]
+    let percentage = (modified + zeroed) as f64 / files.seed_size.max(1) as f64 * 100.0;
+    info!(
+        "modified: {} bytes ({percentage:.2}% of the seed), {zeroed} of them zeroed without using overlay space",
+        modified + zeroed
+    );
+}
+
+/// Count bytes stored in the overlay and bytes marked as zeroed in the mask
+fn count_modified(files: &Files) -> (u64, u64) {
+    let mut mask_buffer = vec![0; files.block_size as usize];
+    let (mut modified, mut zeroed) = (0, 0);
+
+    let block_limit = files.mask_size.div_ceil(u64::from(files.block_size));
+    let mut progress = Progress::new(files.progress, "scanning", block_limit, files.block_size);
+    for block in 0..block_limit {
+        progress.update(block);
+        let offset = block * u64::from(files.block_size);
+
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            info!(
+                "interrupted at offset {offset}, found {} modified bytes so far",
+                modified + zeroed
+            );
+            exit(interrupt::EXIT_CODE);
+        }
+
+        mask_buffer.fill(0);
+        if let Err(error) = files.mask.read_at(&mut mask_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from mask file at offset {offset}: {error}",
+                files.block_size
+            );
+            if !files.ignore_errors {
+                exit(1);
+            }
+            continue;
+        }
+        for &byte in &mask_buffer {
+            match byte {
+                MASK => modified += 1,
+                ZEROED => zeroed += 1,
+                _ => {}
+            }
+        }
+    }
+    progress.finish();
+    (modified, zeroed)
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn format_ratio(uncompressed: u64, compressed: u64) -> String {
+    if compressed == 0 {
+        return "nothing stored".to_string();
+    }
+    format!("ratio {:.2}:1", uncompressed as f64 / compressed as f64)
+}
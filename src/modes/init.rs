@@ -0,0 +1,120 @@
+use crate::{
+    Files, arguments::Arguments, block_device::get_size, checksum::Checksums, interrupt,
+    progress::Progress,
+};
+use log::{error, info, warn};
+use std::{path::Path, process::exit};
+
+pub fn validate(arguments: &Arguments, sidecar_files: &[(&str, &Path)]) {
+    let seed_file = arguments.seed_file.as_path();
+    let block_size = arguments.block_size;
+
+    let mut session_files = sidecar_files.to_vec();
+    for (name, path) in [
+        ("container", &arguments.container_file),
+        ("overlay", &arguments.overlay_file),
+        ("mask", &arguments.mask_file),
+    ] {
+        if let Some(path) = path {
+            session_files.push((name, path));
+        }
+    }
+    for (name, path) in session_files {
+        if path.exists() && get_size(path) > 0 {
+            error!(
+                "{name} file {} already exists and isn't empty, refusing to overwrite it",
+                path.to_string_lossy()
+            );
+            exit(1);
+        }
+    }
+
+    if !block_size.is_power_of_two() || block_size < 512 {
+        error!("block size must be a power of two and at least 512 bytes, got {block_size}");
+        exit(1);
+    }
+    if block_utils::is_block_device(seed_file).unwrap_or(false)
+        && let Ok(device_info) = block_utils::get_device_info(seed_file)
+        && let Some(logical_block_size) = device_info.logical_block_size
+        && !u64::from(block_size).is_multiple_of(logical_block_size)
+    {
+        error!(
+            "block size must be a multiple of the seed's logical block size ({logical_block_size} bytes), got {block_size}"
+        );
+        exit(1);
+    }
+}
+
+pub fn main(files: &Files, preallocate: bool, sparse: bool, hash: bool) {
+    if !files.seed_size.is_multiple_of(u64::from(files.block_size)) {
+        warn!(
+            "seed size ({} bytes) isn't a multiple of the block size ({} bytes), the last block only has {} bytes of seed data (reads past the end return zeroes and writes past it are discarded)",
+            files.seed_size,
+            files.block_size,
+            files.seed_size % u64::from(files.block_size)
+        );
+    }
+    for (name, file) in [("overlay", &files.overlay), ("mask", &files.mask)] {
+        if preallocate {
+            info!("preallocating {} bytes for {name} file...", files.seed_size);
+            if let Err(error) = file.allocate(files.seed_size) {
+                error!("couldn't preallocate {name} file: {error}");
+                exit(1);
+            }
+        } else if sparse && let Err(error) = file.set_len(files.seed_size) {
+            error!(
+                "couldn't resize {name} file to {} bytes: {error}",
+                files.seed_size
+            );
+            exit(1);
+        }
+    }
+
+    if hash && let Some(checksums) = &files.checksums {
+        hash_seed(files, checksums);
+    }
+
+    files.sync();
+    info!("successfully initialized session");
+}
+
+fn hash_seed(files: &Files, checksums: &Checksums) {
+    info!("recording seed block checksums...");
+
+    let mut seed_buffer = vec![0; files.block_size as usize];
+
+    let block_limit = files.seed_blocks();
+    let mut progress = Progress::new(files.progress, "hashing", block_limit, files.block_size);
+    for block in 0..block_limit {
+        progress.update(block);
+        let offset = block * u64::from(files.block_size);
+
+        if interrupt::interrupted() {
+            progress.interrupted(block);
+            files.sync();
+            info!(
+                "interrupted at offset {offset} after hashing {block} blocks, remaining checksums will be recorded as blocks are read"
+            );
+            exit(interrupt::EXIT_CODE);
+        }
+
+        // the last block of an unaligned seed is hashed zero-padded, like the device reads it
+        seed_buffer.fill(0);
+        if let Err(error) = files.seed.read_at(&mut seed_buffer, offset) {
+            error!(
+                "couldn't read {} bytes from seed file at offset {offset}: {error}",
+                files.block_size
+            );
+            if !files.ignore_errors {
+                exit(1);
+            }
+            continue;
+        }
+        if let Err(error) = checksums.set(block, &seed_buffer) {
+            error!("couldn't record checksum of seed block {block}: {error}");
+            exit(1);
+        }
+    }
+    progress.finish();
+    info!("successfully recorded checksums of {block_limit} seed blocks");
+}
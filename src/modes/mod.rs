@@ -1,3 +1,7 @@
 pub mod apply;
 pub mod clean;
+pub mod convert;
 pub mod device;
+pub mod gc;
+pub mod info;
+pub mod init;
@@ -0,0 +1,163 @@
+use crate::arguments::ProgressMode;
+use log::{LevelFilter, info};
+use std::{
+    io::{self, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+const BAR_WIDTH: usize = 30;
+const BAR_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress {
+    mode: ProgressMode,
+    operation: &'static str,
+    total: u64,
+    unit: u64,
+
+    start: Instant,
+    first: Option<u64>,
+    last_percent: f64,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode, operation: &'static str, total: u64, unit: u32) -> Self {
+        let mode = match mode {
+            ProgressMode::Auto
+                if io::stderr().is_terminal() && log::max_level() >= LevelFilter::Info =>
+            {
+                ProgressMode::Bar
+            }
+            ProgressMode::Auto => ProgressMode::Text,
+            mode => mode,
+        };
+
+        Self {
+            mode,
+            operation,
+            total,
+            unit: u64::from(unit),
+            start: Instant::now(),
+            first: None,
+            last_percent: 0.0,
+            last_draw: None,
+        }
+    }
+
+    pub fn update(&mut self, current: u64) {
+        let first = *self.first.get_or_insert(current);
+        #[allow(clippy::cast_precision_loss)]
+        let percent = current as f64 / self.total as f64 * 100.0;
+
+        match self.mode {
+            ProgressMode::Text | ProgressMode::Json => {
+                if percent - self.last_percent <= 0.1 {
+                    return;
+                }
+                self.last_percent = percent;
+            }
+            ProgressMode::Bar => {
+                if self
+                    .last_draw
+                    .is_some_and(|last_draw| last_draw.elapsed() < BAR_INTERVAL)
+                {
+                    return;
+                }
+                self.last_draw = Some(Instant::now());
+            }
+            ProgressMode::Auto | ProgressMode::None => return,
+        }
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let (bytes_per_second, eta) = if current > first && elapsed > 0.0 {
+            let items_per_second = (current - first) as f64 / elapsed;
+            (
+                items_per_second * self.unit as f64,
+                Some((self.total.saturating_sub(current)) as f64 / items_per_second),
+            )
+        } else {
+            (0.0, None)
+        };
+
+        match self.mode {
+            ProgressMode::Text => info!(
+                "{} blocks: {percent:.1}% ({current}/{})",
+                self.operation, self.total
+            ),
+            ProgressMode::Json => println!(
+                "{{\"event\":\"progress\",\"operation\":\"{}\",\"current\":{current},\"total\":{},\"percent\":{percent:.1},\"bytes_per_second\":{bytes_per_second:.0},\"eta_seconds\":{}}}",
+                self.operation,
+                self.total,
+                eta.map_or_else(|| "null".to_string(), |eta| format!("{eta:.0}")),
+            ),
+            ProgressMode::Bar => {
+                #[allow(
+                    clippy::cast_possible_truncation,
+                    clippy::cast_sign_loss,
+                    clippy::cast_precision_loss
+                )]
+                let filled = ((current as f64 / self.total as f64) * BAR_WIDTH as f64) as usize;
+                let filled = filled.min(BAR_WIDTH);
+                eprint!(
+                    "\r{} blocks: [{}{}] {percent:5.1}% ({current}/{}) {} ETA {}\x1b[K",
+                    self.operation,
+                    "#".repeat(filled),
+                    "-".repeat(BAR_WIDTH - filled),
+                    self.total,
+                    format_rate(bytes_per_second),
+                    eta.map_or_else(|| "--:--:--".to_string(), format_duration),
+                );
+                let _ = io::stderr().flush();
+            }
+            ProgressMode::Auto | ProgressMode::None => {}
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.end("finish", self.total);
+    }
+
+    pub fn interrupted(&mut self, current: u64) {
+        self.end("interrupted", current);
+    }
+
+    fn end(&mut self, event: &str, current: u64) {
+        match self.mode {
+            ProgressMode::Json => println!(
+                "{{\"event\":\"{event}\",\"operation\":\"{}\",\"current\":{current},\"total\":{},\"elapsed_seconds\":{:.1}}}",
+                self.operation,
+                self.total,
+                self.start.elapsed().as_secs_f64()
+            ),
+            ProgressMode::Bar if self.last_draw.is_some() => {
+                eprintln!();
+                self.last_draw = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn format_rate(bytes_per_second: f64) -> String {
+    let mut rate = bytes_per_second;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if rate < 1024.0 {
+            return format!("{rate:.1} {unit}/s");
+        }
+        rate /= 1024.0;
+    }
+    format!("{rate:.1} TiB/s")
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,211 @@
+use log::info;
+use lru::LruCache;
+use std::{io, num::NonZeroUsize, sync::Arc};
+
+/// Unit seed data is cached in
+pub const BLOCK_SIZE: usize = 65536;
+
+/// Seed data of one cache block (shorter at the end of the seed)
+pub type Block = Arc<[u8]>;
+
+/// In-memory cache of seed blocks, evicting the least recently used ones
+pub struct Cache {
+    blocks: LruCache<u64, Block>,
+    /// Blocks to read ahead when a sequential read misses the cache
+    read_ahead: u64,
+    /// Where the last read ended, to detect sequential reads
+    last_end: u64,
+
+    hits: u64,
+    misses: u64,
+    blocks_read_ahead: u64,
+}
+
+impl Cache {
+    pub fn new(size: u64, read_ahead: u64) -> Self {
+        let capacity = usize::try_from(size / BLOCK_SIZE as u64)
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(NonZeroUsize::MIN);
+        Self {
+            blocks: LruCache::new(capacity),
+            read_ahead: read_ahead.div_ceil(BLOCK_SIZE as u64),
+            last_end: u64::MAX,
+            hits: 0,
+            misses: 0,
+            blocks_read_ahead: 0,
+        }
+    }
+
+    /// Look up the blocks covering a read, returning them and the runs of blocks to read for the
+    /// missing ones (with the blocks after the read as well if it follows the previous one)
+    pub fn lookup(&mut self, offset: u64, len: usize) -> (Vec<Option<Block>>, Vec<(u64, u64)>) {
+        let first = offset / BLOCK_SIZE as u64;
+        let last = (offset + len as u64 - 1) / BLOCK_SIZE as u64;
+        let sequential = offset == self.last_end;
+        self.last_end = offset + len as u64;
+
+        let blocks: Vec<_> = (first..=last)
+            .map(|index| self.blocks.get(&index).cloned())
+            .collect();
+        let mut missing: Vec<(u64, u64)> = Vec::new();
+        for (index, block) in (first..).zip(&blocks) {
+            if block.is_some() {
+                continue;
+            }
+            match missing.last_mut() {
+                Some((_, end)) if *end == index => *end += 1,
+                _ => missing.push((index, index + 1)),
+            }
+        }
+        let missing_count: u64 = missing.iter().map(|(start, end)| end - start).sum();
+        self.hits += blocks.len() as u64 - missing_count;
+        self.misses += missing_count;
+        if missing.is_empty() || !sequential {
+            return (blocks, missing);
+        }
+
+        let ahead = (last + 1..last + 1 + self.read_ahead)
+            .take_while(|index| !self.blocks.contains(index))
+            .count() as u64;
+        if ahead > 0 {
+            match missing.last_mut() {
+                Some((_, end)) if *end == last + 1 => *end += ahead,
+                _ => missing.push((last + 1, last + 1 + ahead)),
+            }
+        }
+        (blocks, missing)
+    }
+
+    /// Cache blocks read from the seed, counting the ones past `requested_end` as read ahead
+    pub fn insert(&mut self, first: u64, data: &[u8], requested_end: u64) -> Vec<Block> {
+        let mut inserted = Vec::new();
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let index = first + i as u64;
+            let block: Block = Arc::from(chunk);
+            if index >= requested_end {
+                self.blocks_read_ahead += 1;
+            }
+            self.blocks.put(index, Arc::clone(&block));
+            inserted.push(block);
+        }
+        inserted
+    }
+
+    /// Forget every cached block, after the seed was written to
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.last_end = u64::MAX;
+    }
+
+    pub fn report(&self) {
+        #[allow(clippy::cast_precision_loss)]
+        let hit_rate = self.hits as f64 / (self.hits + self.misses).max(1) as f64 * 100.0;
+        info!(
+            "seed cache: {} hits and {} misses ({hit_rate:.1}% hit rate), {} blocks read ahead",
+            self.hits, self.misses, self.blocks_read_ahead
+        );
+    }
+}
+
+/// Copy the part of `blocks` (starting at block `first`) covering a read into `buffer`, returning
+/// how many bytes were available before the end of the seed
+pub fn copy_out(blocks: &[Block], first: u64, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut done = 0;
+    for (i, block) in blocks.iter().enumerate() {
+        let block_offset = (first + i as u64) * BLOCK_SIZE as u64;
+        let start = usize::try_from((offset + done as u64).saturating_sub(block_offset))
+            .map_err(io::Error::other)?;
+        if start >= block.len() {
+            break;
+        }
+        let len = (block.len() - start).min(buffer.len() - done);
+        buffer[done..done + len].copy_from_slice(&block[start..start + len]);
+        done += len;
+        if done == buffer.len() || block.len() < BLOCK_SIZE {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u64 = BLOCK_SIZE as u64;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| u8::try_from(i % 251).unwrap()).collect()
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = Cache::new(16 * BLOCK, 0);
+        let (blocks, missing) = cache.lookup(10, 2 * BLOCK_SIZE);
+        assert!(blocks.iter().all(Option::is_none));
+        assert_eq!(missing, [(0, 3)]);
+        cache.insert(0, &data(3 * BLOCK_SIZE), 3);
+
+        let (blocks, missing) = cache.lookup(BLOCK + 10, 100);
+        assert!(missing.is_empty());
+        let blocks: Vec<_> = blocks.into_iter().map(Option::unwrap).collect();
+        let mut buffer = vec![0; 100];
+        assert_eq!(copy_out(&blocks, 1, &mut buffer, BLOCK + 10).unwrap(), 100);
+        assert_eq!(
+            buffer,
+            data(3 * BLOCK_SIZE)[BLOCK_SIZE + 10..BLOCK_SIZE + 110]
+        );
+        assert_eq!((cache.hits, cache.misses), (1, 3));
+    }
+
+    #[test]
+    fn only_missing_blocks_are_read() {
+        let mut cache = Cache::new(16 * BLOCK, 4 * BLOCK);
+        cache.insert(1, &data(BLOCK_SIZE), 2);
+        cache.insert(3, &data(BLOCK_SIZE), 4);
+
+        let (blocks, missing) = cache.lookup(0, 5 * BLOCK_SIZE);
+        assert_eq!(blocks.iter().filter(|block| block.is_some()).count(), 2);
+        assert_eq!(missing, [(0, 1), (2, 3), (4, 5)]);
+    }
+
+    #[test]
+    fn sequential_reads_read_ahead() {
+        let mut cache = Cache::new(16 * BLOCK, 2 * BLOCK);
+        assert_eq!(cache.lookup(0, BLOCK_SIZE).1, [(0, 1)]);
+        cache.insert(0, &data(BLOCK_SIZE), 1);
+
+        assert_eq!(cache.lookup(BLOCK, BLOCK_SIZE).1, [(1, 4)]);
+        cache.insert(1, &data(3 * BLOCK_SIZE), 2);
+        assert_eq!(cache.blocks_read_ahead, 2);
+        assert!(cache.lookup(2 * BLOCK, BLOCK_SIZE).1.is_empty());
+
+        // read-ahead stops at blocks that are already cached, and isn't done for random reads
+        cache.insert(6, &data(BLOCK_SIZE), 7);
+        assert_eq!(cache.lookup(3 * BLOCK, 2 * BLOCK_SIZE).1, [(4, 6)]);
+        assert_eq!(cache.lookup(9 * BLOCK, BLOCK_SIZE).1, [(9, 10)]);
+    }
+
+    #[test]
+    fn reads_at_the_end_of_the_seed_are_short() {
+        let mut cache = Cache::new(16 * BLOCK, 0);
+        let seed = data(BLOCK_SIZE + 100);
+        let blocks = cache.insert(0, &seed, 2);
+        assert_eq!(blocks.len(), 2);
+
+        let mut buffer = vec![0; 200];
+        assert_eq!(
+            copy_out(&blocks[1..], 1, &mut buffer, BLOCK + 50).unwrap(),
+            50
+        );
+        assert_eq!(buffer[..50], seed[BLOCK_SIZE + 50..]);
+        assert_eq!(
+            copy_out(&blocks[1..], 1, &mut buffer, BLOCK + 200).unwrap(),
+            0
+        );
+
+        // blocks past the end are looked up again, since there was nothing to cache
+        assert_eq!(cache.lookup(BLOCK, 2 * BLOCK_SIZE).1, [(2, 3)]);
+    }
+}
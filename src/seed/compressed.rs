@@ -0,0 +1,515 @@
+use super::Source;
+use log::debug;
+use lru::LruCache;
+use std::{
+    fs,
+    io::{self, Read},
+    num::NonZeroUsize,
+    os::unix::fs::FileExt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+const XZ_FOOTER_MAGIC: [u8; 2] = *b"YZ";
+
+/// Decompressed frames kept around, so small reads don't decompress the same frame again
+const CACHED_FRAMES: usize = 4;
+/// Largest frame decompressed into memory at once (with up to `CACHED_FRAMES` of them kept)
+const MAX_FRAME: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zstd,
+    Xz,
+}
+
+/// Independently decompressible part of the image
+struct Frame {
+    /// Position of the compressed data in the file
+    offset: u64,
+    compressed_len: u64,
+    /// Position of the decompressed data in the image
+    start: u64,
+    len: u64,
+    /// Flags of the xz stream the block belongs to (unused for zstd)
+    stream_flags: [u8; 2],
+    /// Size of the xz block without padding (unused for zstd)
+    unpadded_len: u64,
+}
+
+/// Seekable zstd (with a seek table) or xz (split into blocks) image, decompressed a frame at a
+/// time as it's read
+pub struct Compressed {
+    file: fs::File,
+    format: Format,
+    frames: Vec<Frame>,
+    len: u64,
+    cache: Mutex<LruCache<usize, Arc<[u8]>>>,
+}
+
+impl Compressed {
+    /// Open `file` if it's compressed, returning it unchanged otherwise
+    pub fn open(file: fs::File) -> io::Result<Result<Self, fs::File>> {
+        if !file.metadata()?.is_file() {
+            return Ok(Err(file));
+        }
+        let mut magic = [0; 6];
+        let read = FileExt::read_at(&file, &mut magic, 0)?;
+        let (format, frames) = if magic[..read].starts_with(&ZSTD_MAGIC) {
+            (Format::Zstd, zstd_frames(&file)?)
+        } else if magic[..read].starts_with(&XZ_MAGIC) {
+            (Format::Xz, xz_frames(&file)?)
+        } else {
+            return Ok(Err(file));
+        };
+
+        let len = frames.last().map_or(0, |frame| frame.start + frame.len);
+        let largest = frames.iter().map(|frame| frame.len).max().unwrap_or(0);
+        debug!(
+            "{} seed has {} frames ({len} bytes decompressed)",
+            format.name(),
+            frames.len()
+        );
+        if largest > MAX_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} seed has frames of up to {largest} bytes, but frames are decompressed into memory whole and can be at most {MAX_FRAME} bytes (recompress it with smaller frames or blocks, e.g. xz --block-size=16MiB)",
+                    format.name()
+                ),
+            ));
+        }
+        Ok(Ok(Self {
+            file,
+            format,
+            frames,
+            len,
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHED_FRAMES).unwrap())),
+        }))
+    }
+
+    fn frame(&self, index: usize) -> io::Result<Arc<[u8]>> {
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(data) = cache.get(&index) {
+            return Ok(Arc::clone(data));
+        }
+
+        let frame = &self.frames[index];
+        let mut compressed =
+            vec![0; usize::try_from(frame.compressed_len).map_err(io::Error::other)?];
+        self.file.read_exact_at(&mut compressed, frame.offset)?;
+        let capacity = usize::try_from(frame.len).map_err(io::Error::other)?;
+        let data = match self.format {
+            Format::Zstd => zstd::bulk::decompress(&compressed, capacity)?,
+            Format::Xz => {
+                let mut data = Vec::with_capacity(capacity);
+                // a corrupted block could decompress to more than its index says
+                xz2::read::XzDecoder::new(&xz_block_stream(frame, &compressed)[..])
+                    .take(frame.len + 1)
+                    .read_to_end(&mut data)?;
+                data
+            }
+        };
+        if data.len() as u64 != frame.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} frame at offset {} decompressed to {} bytes instead of {}",
+                    self.format.name(),
+                    frame.offset,
+                    data.len(),
+                    frame.len
+                ),
+            ));
+        }
+
+        let data: Arc<[u8]> = data.into();
+        cache.put(index, Arc::clone(&data));
+        Ok(data)
+    }
+}
+
+impl Source for Compressed {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = usize::try_from(self.len.saturating_sub(offset))
+            .map_or(buffer.len(), |left| left.min(buffer.len()));
+
+        let mut index = self
+            .frames
+            .partition_point(|frame| frame.start + frame.len <= offset);
+        let mut done = 0;
+        while done < len {
+            let frame = &self.frames[index];
+            let data = self.frame(index)?;
+            let from =
+                usize::try_from(offset + done as u64 - frame.start).map_err(io::Error::other)?;
+            let chunk = (data.len() - from).min(len - done);
+            buffer[done..done + chunk].copy_from_slice(&data[from..from + chunk]);
+            done += chunk;
+            index += 1;
+        }
+        Ok(len)
+    }
+
+    fn compression(&self) -> Option<String> {
+        Some(format!(
+            "{} ({} frames)",
+            self.format.name(),
+            self.frames.len()
+        ))
+    }
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_exact_at<const N: usize>(file: &fs::File, offset: u64) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(bytes)
+}
+
+/// Read the seek table at the end of a seekable zstd file
+fn zstd_frames(file: &fs::File) -> io::Result<Vec<Frame>> {
+    let not_seekable = || {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "zstd seed isn't seekable (it has no seek table, compress it in the seekable format)",
+        )
+    };
+
+    let file_len = file.metadata()?.len();
+    let footer: [u8; 9] = read_exact_at(file, file_len.checked_sub(9).ok_or_else(not_seekable)?)?;
+    if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != ZSTD_SEEKABLE_MAGIC {
+        return Err(not_seekable());
+    }
+    let count = u64::from(u32::from_le_bytes(footer[..4].try_into().unwrap()));
+    // each entry optionally has a checksum of the decompressed frame, which zstd checks itself
+    let entry_len = if footer[4] & 0x80 == 0 { 8 } else { 12 };
+
+    let table_len = count * entry_len;
+    let table_start = file_len
+        .checked_sub(9 + table_len + 8)
+        .ok_or_else(|| invalid("zstd seek table is larger than the file"))?;
+    let header: [u8; 8] = read_exact_at(file, table_start)?;
+    if u32::from_le_bytes(header[..4].try_into().unwrap()) != ZSTD_SKIPPABLE_MAGIC
+        || u64::from(u32::from_le_bytes(header[4..].try_into().unwrap())) != table_len + 9
+    {
+        return Err(invalid("zstd seek table is corrupted"));
+    }
+    let mut table = vec![0; usize::try_from(table_len).map_err(io::Error::other)?];
+    file.read_exact_at(&mut table, table_start + 8)?;
+
+    let mut frames = Vec::new();
+    let (mut offset, mut start) = (0, 0);
+    for entry in table.chunks_exact(usize::try_from(entry_len).map_err(io::Error::other)?) {
+        let compressed_len = u64::from(u32::from_le_bytes(entry[..4].try_into().unwrap()));
+        let len = u64::from(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+        frames.push(Frame {
+            offset,
+            compressed_len,
+            start,
+            len,
+            stream_flags: [0; 2],
+            unpadded_len: 0,
+        });
+        offset += compressed_len;
+        start += len;
+    }
+    if offset != table_start {
+        return Err(invalid("zstd seek table doesn't match the file"));
+    }
+    Ok(frames)
+}
+
+/// Read the indexes of every stream in an xz file, working backwards from the end
+fn xz_frames(file: &fs::File) -> io::Result<Vec<Frame>> {
+    let mut streams = Vec::new();
+    let mut end = file.metadata()?.len();
+    while end > 0 {
+        // streams may be followed by padding made of null bytes
+        let padding: [u8; 4] = read_exact_at(file, end.saturating_sub(4))?;
+        if end >= 4 && padding == [0; 4] {
+            end -= 4;
+            continue;
+        }
+
+        let footer: [u8; 12] = read_exact_at(
+            file,
+            end.checked_sub(12)
+                .ok_or_else(|| invalid("xz stream footer is missing"))?,
+        )?;
+        if footer[10..] != XZ_FOOTER_MAGIC {
+            return Err(invalid("xz stream footer is corrupted"));
+        }
+        let stream_flags = [footer[8], footer[9]];
+        let index_len = (u64::from(u32::from_le_bytes(footer[4..8].try_into().unwrap())) + 1) * 4;
+        let index_start = end
+            .checked_sub(12 + index_len)
+            .ok_or_else(|| invalid("xz index is larger than the file"))?;
+        let mut index = vec![0; usize::try_from(index_len).map_err(io::Error::other)?];
+        file.read_exact_at(&mut index, index_start)?;
+        let blocks = parse_xz_index(&index)?;
+
+        let blocks_len: u64 = blocks
+            .iter()
+            .map(|(unpadded, _)| unpadded.next_multiple_of(4))
+            .sum();
+        let stream_start = index_start
+            .checked_sub(blocks_len + 12)
+            .ok_or_else(|| invalid("xz index doesn't match the file"))?;
+        let header: [u8; 12] = read_exact_at(file, stream_start)?;
+        if header[..6] != XZ_MAGIC || header[6..8] != stream_flags {
+            return Err(invalid("xz stream header is corrupted"));
+        }
+
+        let mut offset = stream_start + 12;
+        let mut frames = Vec::new();
+        for (unpadded_len, len) in blocks {
+            frames.push(Frame {
+                offset,
+                compressed_len: unpadded_len.next_multiple_of(4),
+                start: 0,
+                len,
+                stream_flags,
+                unpadded_len,
+            });
+            offset += unpadded_len.next_multiple_of(4);
+        }
+        streams.push(frames);
+        end = stream_start;
+    }
+
+    let mut frames: Vec<Frame> = streams.into_iter().rev().flatten().collect();
+    let mut start = 0;
+    for frame in &mut frames {
+        frame.start = start;
+        start += frame.len;
+    }
+    Ok(frames)
+}
+
+/// Parse an xz index into the unpadded and uncompressed sizes of its blocks
+fn parse_xz_index(index: &[u8]) -> io::Result<Vec<(u64, u64)>> {
+    let (data, crc) = index.split_at(index.len() - 4);
+    if data.first() != Some(&0) || crc32fast::hash(data).to_le_bytes() != crc {
+        return Err(invalid("xz index is corrupted"));
+    }
+
+    let mut position = 1;
+    let mut varint = || {
+        let mut value = 0u64;
+        for shift in (0..63).step_by(7) {
+            let byte = *data
+                .get(position)
+                .ok_or_else(|| invalid("xz index is truncated"))?;
+            position += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("xz index is corrupted"))
+    };
+    let count = varint()?;
+    (0..count).map(|_| Ok((varint()?, varint()?))).collect()
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn push_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Wrap a single xz block in a stream of its own, so it can be decoded on its own
+fn xz_block_stream(frame: &Frame, block: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(block.len() + 64);
+    stream.extend_from_slice(&XZ_MAGIC);
+    stream.extend_from_slice(&frame.stream_flags);
+    stream.extend_from_slice(&crc32fast::hash(&frame.stream_flags).to_le_bytes());
+    stream.extend_from_slice(block);
+
+    let mut index = vec![0];
+    push_varint(&mut index, 1);
+    push_varint(&mut index, frame.unpadded_len);
+    push_varint(&mut index, frame.len);
+    index.resize(index.len().next_multiple_of(4), 0);
+    index.extend_from_slice(&crc32fast::hash(&index).to_le_bytes());
+    stream.extend_from_slice(&index);
+
+    let backward_size =
+        u32::try_from(index.len() / 4 - 1).expect("index of a single block is tiny");
+    let mut footer = backward_size.to_le_bytes().to_vec();
+    footer.extend_from_slice(&frame.stream_flags);
+    stream.extend_from_slice(&crc32fast::hash(&footer).to_le_bytes());
+    stream.extend_from_slice(&footer);
+    stream.extend_from_slice(&XZ_FOOTER_MAGIC);
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_path::TempPath;
+    use std::io::Write;
+
+    fn open(path: &TempPath) -> io::Result<Result<Compressed, fs::File>> {
+        Compressed::open(fs::File::open(path).unwrap())
+    }
+
+    fn image() -> Vec<u8> {
+        (0..300_000u32)
+            .map(|i| u8::try_from((i % 251) ^ (i / 1000 % 7)).unwrap())
+            .collect()
+    }
+
+    /// Compress `chunks` as separate zstd frames followed by a seek table
+    fn seekable_zstd(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut table = Vec::new();
+        for chunk in chunks {
+            let frame = zstd::bulk::compress(chunk, 3).unwrap();
+            table.extend_from_slice(&u32::try_from(frame.len()).unwrap().to_le_bytes());
+            table.extend_from_slice(&u32::try_from(chunk.len()).unwrap().to_le_bytes());
+            file.extend_from_slice(&frame);
+        }
+        file.extend_from_slice(&ZSTD_SKIPPABLE_MAGIC.to_le_bytes());
+        file.extend_from_slice(&u32::try_from(table.len() + 9).unwrap().to_le_bytes());
+        file.extend_from_slice(&table);
+        file.extend_from_slice(&u32::try_from(chunks.len()).unwrap().to_le_bytes());
+        file.push(0);
+        file.extend_from_slice(&ZSTD_SEEKABLE_MAGIC.to_le_bytes());
+        file
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read(compressed: &Compressed, offset: usize, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        let read = compressed.read_at(&mut buffer, offset as u64).unwrap();
+        buffer.truncate(read);
+        buffer
+    }
+
+    fn check_reads(compressed: &Compressed, image: &[u8]) {
+        assert_eq!(compressed.len().unwrap(), image.len() as u64);
+        for (offset, len) in [(0, 10), (99_990, 20), (50_000, 200_000), (299_990, 100)] {
+            let end = (offset + len).min(image.len());
+            assert_eq!(read(compressed, offset, len), image[offset..end]);
+        }
+    }
+
+    #[test]
+    fn seekable_zstd_frames() {
+        let image = image();
+        let file = TempPath::with_contents(
+            "compressed-seed-zstd",
+            &seekable_zstd(&[
+                &image[..100_000],
+                &image[100_000..250_000],
+                &image[250_000..],
+            ]),
+        );
+        let compressed = open(&file).unwrap().ok().unwrap();
+        let frames: Vec<_> = compressed
+            .frames
+            .iter()
+            .map(|frame| (frame.start, frame.len))
+            .collect();
+        assert_eq!(
+            frames,
+            [(0, 100_000), (100_000, 150_000), (250_000, 50_000)]
+        );
+        check_reads(&compressed, &image);
+    }
+
+    #[test]
+    fn frames_too_large_to_decompress_are_refused() {
+        let mut contents = seekable_zstd(&[&image()[..1000]]);
+        // claim the frame decompresses to far more than it does, as a corrupted table could
+        let entry = contents.len() - 9 - 8 + 4;
+        contents[entry..entry + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let file = TempPath::with_contents("compressed-seed-zstd-large", &contents);
+        let error = open(&file).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn zstd_without_seek_table_is_refused() {
+        let file = TempPath::with_contents(
+            "compressed-seed-zstd-plain",
+            &zstd::bulk::compress(&image(), 3).unwrap(),
+        );
+        let error = open(&file).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn xz_streams_with_padding() {
+        let image = image();
+        let mut contents = xz(&image[..120_000]);
+        contents.extend_from_slice(&[0; 8]);
+        contents.extend_from_slice(&xz(&image[120_000..]));
+        let file = TempPath::with_contents("compressed-seed-xz", &contents);
+        let compressed = open(&file).unwrap().ok().unwrap();
+        let frames: Vec<_> = compressed
+            .frames
+            .iter()
+            .map(|frame| (frame.start, frame.len))
+            .collect();
+        assert_eq!(frames, [(0, 120_000), (120_000, 180_000)]);
+        check_reads(&compressed, &image);
+    }
+
+    #[test]
+    fn xz_index() {
+        let mut index = vec![0];
+        for value in [2, 1000, 5000, 300, 70_000] {
+            push_varint(&mut index, value);
+        }
+        index.resize(index.len().next_multiple_of(4), 0);
+        let crc = crc32fast::hash(&index).to_le_bytes();
+        index.extend_from_slice(&crc);
+        assert_eq!(
+            parse_xz_index(&index).unwrap(),
+            [(1000, 5000), (300, 70_000)]
+        );
+
+        let last = index.len() - 1;
+        index[last] ^= 1;
+        assert_eq!(
+            parse_xz_index(&index).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn uncompressed_file_is_returned() {
+        let file = TempPath::with_contents("compressed-seed-plain", &image());
+        assert!(open(&file).unwrap().is_err());
+    }
+}
@@ -0,0 +1,411 @@
+use super::Source;
+use crate::checksum;
+use log::{debug, warn};
+use std::{
+    fs, io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+/// Unit remote data is fetched and cached in
+const BLOCK_SIZE: usize = 65536;
+/// Most blocks fetched by a single request
+const MAX_BLOCKS_PER_REQUEST: u64 = 16;
+const CACHE_MAGIC: &[u8; 8] = b"OMHCACHE";
+/// Magic, image size and hash of the image's `ETag` (0 without one) at the start of the map
+const CACHE_HEADER_SIZE: usize = 24;
+
+/// Blocks of the remote image kept in a local file, at the same offsets as in the image, with a map
+/// of which blocks are present (one byte per block, like the mask) after a header identifying the
+/// image, so a cache of a different or changed image is discarded instead of read
+struct BlockCache {
+    data: fs::File,
+    map: fs::File,
+    present: Mutex<Vec<bool>>,
+}
+
+impl BlockCache {
+    fn open(path: &Path, len: u64, etag: Option<&str>) -> io::Result<Self> {
+        let open = |path: &Path| {
+            fs::File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        };
+        let data = open(path)?;
+        let map = open(&crate::with_suffix(path, ".map"))?;
+
+        let mut header = [0; CACHE_HEADER_SIZE];
+        header[..8].copy_from_slice(CACHE_MAGIC);
+        header[8..16].copy_from_slice(&len.to_le_bytes());
+        header[16..].copy_from_slice(
+            &etag
+                .map_or(0, |etag| checksum::hash(etag.as_bytes()))
+                .to_le_bytes(),
+        );
+        let mut stored = [0; CACHE_HEADER_SIZE];
+        let read = FileExt::read_at(&map, &mut stored, 0)?;
+        if stored != header {
+            if read > 0 {
+                warn!(
+                    "discarding HTTP cache {}, it belongs to a different or changed image",
+                    path.display()
+                );
+            }
+            data.set_len(0)?;
+            map.set_len(0)?;
+            map.write_all_at(&header, 0)?;
+        }
+
+        let blocks = len.div_ceil(BLOCK_SIZE as u64);
+        let mut present = vec![0; usize::try_from(blocks).map_err(io::Error::other)?];
+        let read = FileExt::read_at(&map, &mut present, CACHE_HEADER_SIZE as u64)?;
+        present.truncate(read);
+        present.resize(usize::try_from(blocks).map_err(io::Error::other)?, 0);
+        Ok(Self {
+            data,
+            map,
+            present: Mutex::new(present.into_iter().map(|byte| byte != 0).collect()),
+        })
+    }
+
+    fn contains(&self, block: u64) -> bool {
+        let present = self.present.lock().unwrap_or_else(PoisonError::into_inner);
+        usize::try_from(block)
+            .ok()
+            .and_then(|block| present.get(block).copied())
+            .unwrap_or(false)
+    }
+
+    fn insert(&self, first: u64, data: &[u8]) -> io::Result<()> {
+        self.data.write_all_at(data, first * BLOCK_SIZE as u64)?;
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        self.map
+            .write_all_at(&vec![1; blocks], CACHE_HEADER_SIZE as u64 + first)?;
+
+        let mut present = self.present.lock().unwrap_or_else(PoisonError::into_inner);
+        for block in first..first + blocks as u64 {
+            if let Some(present) = usize::try_from(block)
+                .ok()
+                .and_then(|block| present.get_mut(block))
+            {
+                *present = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Image on an HTTP server, read lazily with range requests
+pub struct Http {
+    agent: ureq::Agent,
+    url: String,
+    len: u64,
+    etag: Option<String>,
+    cache: Option<BlockCache>,
+}
+
+impl Http {
+    /// Connect to `url`, caching fetched blocks in `cache_file` if given
+    pub fn open(url: &str, cache_file: Option<&Path>) -> io::Result<Self> {
+        let agent = ureq::Agent::new_with_defaults();
+        // a one byte range request both checks that ranges are supported and returns the size
+        let response = agent
+            .get(url)
+            .header("Range", "bytes=0-0")
+            .call()
+            .map_err(ureq::Error::into_io)?;
+        if response.status() != 206 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{url} doesn't support range requests"),
+            ));
+        }
+        let len = response
+            .headers()
+            .get("Content-Range")
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, len)| len.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{url} didn't report the size of the image"),
+                )
+            })?;
+
+        let etag = etag(&response);
+
+        let cache = match cache_file {
+            Some(cache_file) => Some(BlockCache::open(cache_file, len, etag.as_deref())?),
+            None => None,
+        };
+        Ok(Self {
+            agent,
+            url: url.to_string(),
+            len,
+            etag,
+            cache,
+        })
+    }
+
+    fn is_cached(&self, block: u64) -> bool {
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.contains(block))
+    }
+
+    /// Fetch `blocks` blocks from the server, starting at block `first`
+    fn fetch(&self, first: u64, blocks: u64) -> io::Result<Vec<u8>> {
+        let start = first * BLOCK_SIZE as u64;
+        let end = ((first + blocks) * BLOCK_SIZE as u64).min(self.len);
+        debug!("fetching bytes {start}..{end} from {}", self.url);
+
+        let mut response = self
+            .agent
+            .get(&self.url)
+            .header("Range", &format!("bytes={start}-{}", end - 1))
+            .call()
+            .map_err(ureq::Error::into_io)?;
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "{} answered a range request with status {}",
+                self.url,
+                response.status()
+            )));
+        }
+        if self.etag.is_some() && etag(&response) != self.etag {
+            return Err(io::Error::other(format!(
+                "{} changed while it was being read",
+                self.url
+            )));
+        }
+        let data = response
+            .body_mut()
+            .with_config()
+            .limit(end - start + 1)
+            .read_to_vec()
+            .map_err(ureq::Error::into_io)?;
+        if data.len() as u64 != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} returned {} bytes instead of {}",
+                    self.url,
+                    data.len(),
+                    end - start
+                ),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+fn etag(response: &ureq::http::Response<ureq::Body>) -> Option<String> {
+    response
+        .headers()
+        .get("ETag")
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string)
+}
+
+impl Source for Http {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = usize::try_from(self.len.saturating_sub(offset))
+            .map_or(buffer.len(), |left| left.min(buffer.len()));
+        if len == 0 {
+            return Ok(0);
+        }
+        let first = offset / BLOCK_SIZE as u64;
+        let last = (offset + len as u64 - 1) / BLOCK_SIZE as u64;
+
+        let mut block = first;
+        while block <= last {
+            let block_offset = block * BLOCK_SIZE as u64;
+            let cached = self.is_cached(block);
+
+            // fetch every missing block up to the next cached one in a single request
+            let count = if cached {
+                1
+            } else {
+                (block..=last)
+                    .take_while(|&block| !self.is_cached(block))
+                    .count() as u64
+            }
+            .min(MAX_BLOCKS_PER_REQUEST);
+            let data = match &self.cache {
+                Some(cache) if cached => {
+                    let mut data = vec![0; BLOCK_SIZE];
+                    let read = FileExt::read_at(&cache.data, &mut data, block_offset)?;
+                    data.truncate(read);
+                    data
+                }
+                Some(cache) => {
+                    let data = self.fetch(block, count)?;
+                    cache.insert(block, &data)?;
+                    data
+                }
+                None => self.fetch(block, count)?,
+            };
+
+            // copy the part of the fetched range that overlaps the read
+            let range_start = block_offset.max(offset);
+            let range_end = (block_offset + data.len() as u64).min(offset + len as u64);
+            if range_start < range_end {
+                let from = usize::try_from(range_start - block_offset).map_err(io::Error::other)?;
+                let to = usize::try_from(range_start - offset).map_err(io::Error::other)?;
+                let chunk = usize::try_from(range_end - range_start).map_err(io::Error::other)?;
+                buffer[to..to + chunk].copy_from_slice(&data[from..from + chunk]);
+            }
+            block += count;
+        }
+        Ok(len)
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_path::TempPath;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread,
+    };
+
+    const LEN: usize = 5 * BLOCK_SIZE + 1234;
+
+    /// Serve `data` over HTTP on a local port, honouring `Range` headers if `ranges` is set, and
+    /// return the image URL with a count of requests made
+    fn serve(data: Vec<u8>, etag: &'static str, ranges: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let (status, body, content_range) = match range {
+                    Some((start, end)) if ranges => (
+                        "206 Partial Content",
+                        &data[start..=end.min(data.len() - 1)],
+                        format!(
+                            "Content-Range: bytes {start}-{}/{}\r\n",
+                            end.min(data.len() - 1),
+                            data.len()
+                        ),
+                    ),
+                    _ => ("200 OK", &data[..], String::new()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n{content_range}ETag: {etag}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        (url, requests)
+    }
+
+    fn image(seed: u8) -> Vec<u8> {
+        (0..LEN)
+            .map(|i| u8::try_from(i % 251).unwrap() ^ seed)
+            .collect()
+    }
+
+    fn read(http: &Http, offset: usize, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        let read = http.read_at(&mut buffer, offset as u64).unwrap();
+        buffer.truncate(read);
+        buffer
+    }
+
+    #[test]
+    fn range_reads() {
+        let data = image(0);
+        let (url, _) = serve(data.clone(), "\"a\"", true);
+        let http = Http::open(&url, None).unwrap();
+        assert_eq!(http.len().unwrap(), LEN as u64);
+        for (offset, len) in [
+            (0, 10),
+            (BLOCK_SIZE - 5, 10),
+            (1000, 3 * BLOCK_SIZE),
+            (LEN - 7, 100),
+        ] {
+            let end = (offset + len).min(LEN);
+            assert_eq!(read(&http, offset, len), data[offset..end]);
+        }
+        assert!(read(&http, LEN + 1, 10).is_empty());
+    }
+
+    #[test]
+    fn cached_blocks_are_not_fetched_again() {
+        let data = image(0);
+        let (url, requests) = serve(data.clone(), "\"a\"", true);
+        let path = TempPath::new("http-hits");
+        let http = Http::open(&url, Some(&*path)).unwrap();
+        assert_eq!(read(&http, 0, LEN), data);
+        let fetched = requests.load(Ordering::SeqCst);
+        assert_eq!(
+            read(&http, 100, 2 * BLOCK_SIZE),
+            data[100..100 + 2 * BLOCK_SIZE]
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), fetched);
+
+        // only the size probe is sent when the cache is reopened for the same image
+        let http = Http::open(&url, Some(&*path)).unwrap();
+        assert_eq!(read(&http, 0, LEN), data);
+        assert_eq!(requests.load(Ordering::SeqCst), fetched + 1);
+    }
+
+    #[test]
+    fn cache_of_changed_image_is_discarded() {
+        let path = TempPath::new("http-changed");
+        let (url, _) = serve(image(0), "\"a\"", true);
+        let http = Http::open(&url, Some(&*path)).unwrap();
+        assert_eq!(read(&http, 0, LEN), image(0));
+
+        let (url, _) = serve(image(1), "\"b\"", true);
+        let http = Http::open(&url, Some(&*path)).unwrap();
+        assert_eq!(read(&http, 0, LEN), image(1));
+    }
+
+    #[test]
+    fn server_without_range_support_is_refused() {
+        let (url, _) = serve(image(0), "\"a\"", false);
+        let error = Http::open(&url, None).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,722 @@
+mod cache;
+mod compressed;
+mod http;
+mod nbd;
+mod split;
+
+use cache::Cache;
+use compressed::Compressed;
+use http::Http;
+use log::{debug, info, warn};
+use nbd::Nbd;
+use split::Split;
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    fs,
+    io::{self, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// Smallest unit failed reads are split into
+const SECTOR_SIZE: u64 = 512;
+
+/// Somewhere seed data can be read from
+pub trait Source: Send + Sync {
+    fn len(&self) -> io::Result<u64>;
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Description of how the data is compressed, if it's decompressed on the fly
+    fn compression(&self) -> Option<String> {
+        None
+    }
+
+    /// Number of files the data is split into
+    fn segments(&self) -> usize {
+        1
+    }
+
+    /// Whether a read that fails after retrying means the data is gone, like a bad sector on a
+    /// local disk, rather than a failure that may well pass like a network error
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+impl Source for fs::File {
+    fn len(&self) -> io::Result<u64> {
+        // unlike the metadata, seeking also works for block devices
+        (&*self).seek(SeekFrom::End(0))
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buffer, offset)
+    }
+}
+
+/// Open a file (decompressing seekable zstd and xz files, and joining numbered or globbed
+/// segments), block device, `http://` URL or NBD export, caching blocks fetched over HTTP in
+/// `cache_file`
+pub fn open_source(location: &Path, cache_file: Option<&Path>) -> io::Result<Box<dyn Source>> {
+    match location.to_str() {
+        Some(url) if url.starts_with("http://") => Ok(Box::new(Http::open(url, cache_file)?)),
+        Some(uri) if uri.starts_with("nbd://") || uri.starts_with("nbd+unix://") => {
+            Ok(Box::new(Nbd::open(uri)?))
+        }
+        Some(url) if url.starts_with("https://") => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only http:// URLs are supported",
+        )),
+        _ => match split::segment_paths(location)? {
+            Some(paths) => Ok(Box::new(Split::open(&paths, open_file)?)),
+            None => open_file(location),
+        },
+    }
+}
+
+/// Open a file or block device, decompressing it if it's compressed
+fn open_file(path: &Path) -> io::Result<Box<dyn Source>> {
+    match Compressed::open(fs::File::open(path)?)? {
+        Ok(compressed) => Ok(Box::new(compressed)),
+        Err(file) => Ok(Box::new(file)),
+    }
+}
+
+/// How reads of a single sector are retried before it's considered unreadable
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub delay: Duration,
+}
+
+/// Another copy of the seed's data (e.g. an older image of the same disk), read from when the
+/// seed itself can't be
+struct Mirror {
+    path: PathBuf,
+    source: Box<dyn Source>,
+    /// Sectors this mirror was read from instead of the seed
+    served: Mutex<BTreeSet<u64>>,
+}
+
+/// Sectors found unreadable by a read
+#[derive(Default)]
+struct Failures {
+    /// Sectors the seed itself couldn't read, for the bad-block map
+    seed: Vec<u64>,
+    /// Sectors no mirror could read either
+    all: Vec<u64>,
+}
+
+/// The read-only seed, salvaging as much as possible from failing reads
+///
+/// A failed read is split in halves down to single sectors, which are retried according to the
+/// retry policy. Sectors that still can't be read are read from the first mirror that can read them
+/// (or zero-filled), and if the seed is local they're recorded in the bad-block map and never read
+/// from the seed again.
+pub struct Seed {
+    source: Box<dyn Source>,
+    mirrors: Vec<Mirror>,
+    retry_policy: RetryPolicy,
+    /// Fail reads of unreadable sectors even when IO errors are ignored
+    pub fail_unreadable: bool,
+
+    bad_block_file: PathBuf,
+    bad_sectors: Mutex<BTreeSet<u64>>,
+
+    cache: Option<Mutex<Cache>>,
+
+    /// Part of the source used as the seed (the rest of the source if there's no length), with
+    /// offsets in the bad-block map and mirrors still relative to the whole source
+    offset: u64,
+    length: Option<u64>,
+}
+
+impl Seed {
+    pub fn open(
+        location: &Path,
+        http_cache_file: &Path,
+        mirror_paths: &[PathBuf],
+        bad_block_file: &Path,
+        retry_policy: RetryPolicy,
+        fail_unreadable: bool,
+    ) -> io::Result<Self> {
+        let bad_sectors = match fs::read_to_string(bad_block_file) {
+            Ok(contents) => parse_bad_blocks(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(error) => return Err(error),
+        };
+        if !bad_sectors.is_empty() {
+            info!(
+                "skipping {} unreadable seed sectors recorded in {}",
+                bad_sectors.len(),
+                bad_block_file.to_string_lossy()
+            );
+        }
+
+        let mut mirrors = Vec::with_capacity(mirror_paths.len());
+        for path in mirror_paths {
+            mirrors.push(Mirror {
+                path: path.clone(),
+                source: open_source(path, None).map_err(|error| {
+                    io::Error::new(
+                        error.kind(),
+                        format!("couldn't open mirror {}: {error}", path.to_string_lossy()),
+                    )
+                })?,
+                served: Mutex::new(BTreeSet::new()),
+            });
+        }
+
+        Ok(Self {
+            source: open_source(location, Some(http_cache_file))?,
+            mirrors,
+            retry_policy,
+            fail_unreadable,
+            bad_block_file: bad_block_file.to_path_buf(),
+            bad_sectors: Mutex::new(bad_sectors),
+            cache: None,
+            offset: 0,
+            length: None,
+        })
+    }
+
+    /// Only use `length` bytes of the source (or the rest of it) starting at `offset`
+    pub fn with_window(mut self, offset: u64, length: Option<u64>) -> io::Result<Self> {
+        let source_len = self.source.len()?;
+        let end = offset.saturating_add(length.unwrap_or(0));
+        if end > source_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "seed window ends at offset {end}, past the end of the seed ({source_len} bytes)"
+                ),
+            ));
+        }
+        if offset > 0 || length.is_some() {
+            debug!(
+                "using {} bytes of the seed starting at offset {offset}",
+                length.unwrap_or(source_len - offset)
+            );
+        }
+        self.offset = offset;
+        self.length = length;
+        Ok(self)
+    }
+
+    /// Offset and length (if limited) of the part of the source used as the seed
+    pub fn window(&self) -> (u64, Option<u64>) {
+        (self.offset, self.length)
+    }
+
+    /// Cache up to `size` bytes of seed data in memory, reading `read_ahead` more bytes when
+    /// sequential reads miss the cache
+    pub fn with_cache(mut self, size: u64, read_ahead: u64) -> Self {
+        self.cache = Some(Mutex::new(Cache::new(size, read_ahead)));
+        self
+    }
+
+    /// Drop cached seed data, which is stale after the seed was written to
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        }
+    }
+
+    pub fn report_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .report();
+        }
+    }
+
+    pub fn len(&self) -> io::Result<u64> {
+        match self.length {
+            Some(length) => Ok(length),
+            None => Ok(self.source.len()?.saturating_sub(self.offset)),
+        }
+    }
+
+    pub fn compression(&self) -> Option<String> {
+        self.source.compression()
+    }
+
+    pub fn segments(&self) -> usize {
+        self.source.segments()
+    }
+
+    /// Number of sectors recorded as unreadable
+    pub fn bad_sectors(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Log which ranges each mirror was read from instead of the seed
+    pub fn report_mirrors(&self) {
+        for mirror in &self.mirrors {
+            let served = mirror.served.lock().unwrap_or_else(PoisonError::into_inner);
+            if served.is_empty() {
+                continue;
+            }
+            let ranges = byte_ranges(&served);
+            info!(
+                "mirror {} served {} bytes: {}",
+                mirror.path.to_string_lossy(),
+                served.len() as u64 * SECTOR_SIZE,
+                ranges
+                    .iter()
+                    .map(|(offset, len)| format!("{len} bytes at offset {offset}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    /// Read as much of `buffer` as possible, zero-filling unreadable sectors (and failing with the
+    /// rest of the buffer filled in if there were any)
+    pub fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let buffer = match self.length {
+            Some(length) => {
+                let left = usize::try_from(length.saturating_sub(offset)).unwrap_or(usize::MAX);
+                let len = buffer.len().min(left);
+                &mut buffer[..len]
+            }
+            None => buffer,
+        };
+        let offset = self.offset + offset;
+
+        let Some(cache) = &self.cache else {
+            return self.read_uncached(buffer, offset);
+        };
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let lock = || cache.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let first = offset / cache::BLOCK_SIZE as u64;
+        let (mut blocks, missing) = lock().lookup(offset, buffer.len());
+        let requested_end = first + blocks.len() as u64;
+        let mut result = Ok(());
+        for (start, end) in missing {
+            let len = usize::try_from((end - start) * cache::BLOCK_SIZE as u64)
+                .map_err(io::Error::other)?;
+            let mut data = vec![0; len];
+            let fetched: Vec<cache::Block> =
+                match self.read_uncached(&mut data, start * cache::BLOCK_SIZE as u64) {
+                    Ok(read) => lock().insert(start, &data[..read], requested_end),
+                    // salvaged data is returned, but not cached so unreadable sectors are retried
+                    // later
+                    Err(error) => {
+                        result = result.and(Err(error));
+                        data.chunks(cache::BLOCK_SIZE).map(Arc::from).collect()
+                    }
+                };
+            let skip = usize::try_from(start - first).map_err(io::Error::other)?;
+            for (slot, block) in blocks.iter_mut().skip(skip).zip(fetched) {
+                *slot = Some(block);
+            }
+        }
+
+        let blocks: Vec<_> = blocks.into_iter().map_while(|block| block).collect();
+        let read = cache::copy_out(&blocks, first, buffer, offset)?;
+        result.map(|()| read)
+    }
+
+    fn read_uncached(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut failures = Failures::default();
+        let read = self.read_range(buffer, offset, &mut failures);
+        if self.source.is_local()
+            && let Err(error) = self.record(&failures.seed)
+        {
+            warn!("couldn't update bad-block map: {error}");
+        }
+        if failures.all.is_empty() {
+            return Ok(read);
+        }
+
+        Err(io::Error::other(format!(
+            "{} unreadable sectors from offset {}",
+            failures.all.len(),
+            failures.all[0] * SECTOR_SIZE
+        )))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeSet<u64>> {
+        self.bad_sectors
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn read_range(&self, buffer: &mut [u8], offset: u64, failures: &mut Failures) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+        let first = offset / SECTOR_SIZE;
+        let last = (offset + buffer.len() as u64 - 1) / SECTOR_SIZE;
+
+        if first == last {
+            if !self.lock().contains(&first) {
+                match self.read_retrying(&*self.source, buffer, offset) {
+                    Ok(read) => return read,
+                    Err(error) => {
+                        warn!("seed sector {first} (offset {offset}) is unreadable: {error}");
+                        failures.seed.push(first);
+                    }
+                }
+            }
+            return self.read_mirrors(buffer, offset, failures);
+        }
+
+        if self.lock().range(first..=last).next().is_none() {
+            match read_full(&*self.source, buffer, offset) {
+                Ok(read) => return read,
+                Err(error) => debug!(
+                    "couldn't read {} bytes from seed at offset {offset}, splitting: {error}",
+                    buffer.len()
+                ),
+            }
+        }
+
+        let middle = first + (last - first).div_ceil(2);
+        let (head, tail) = buffer.split_at_mut(
+            usize::try_from(middle * SECTOR_SIZE - offset).expect("split is within the buffer"),
+        );
+        let read = self.read_range(head, offset, failures);
+        if read < head.len() {
+            return read;
+        }
+        read + self.read_range(tail, offset + head.len() as u64, failures)
+    }
+
+    /// Read a sector the seed can't from the first mirror that can, or zero-fill it
+    fn read_mirrors(&self, buffer: &mut [u8], offset: u64, failures: &mut Failures) -> usize {
+        let sector = offset / SECTOR_SIZE;
+        for mirror in &self.mirrors {
+            buffer.fill(0);
+            match self.read_retrying(&*mirror.source, buffer, offset) {
+                Ok(_) => {
+                    debug!(
+                        "read seed sector {sector} (offset {offset}) from mirror {}",
+                        mirror.path.to_string_lossy()
+                    );
+                    mirror
+                        .served
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .insert(sector);
+                    return buffer.len();
+                }
+                Err(error) => warn!(
+                    "seed sector {sector} (offset {offset}) is unreadable from mirror {}: {error}",
+                    mirror.path.to_string_lossy()
+                ),
+            }
+        }
+        buffer.fill(0);
+        failures.all.push(sector);
+        buffer.len()
+    }
+
+    fn read_retrying(
+        &self,
+        source: &dyn Source,
+        buffer: &mut [u8],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let mut delay = self.retry_policy.delay;
+        for retry in 1..=self.retry_policy.retries {
+            match read_full(source, buffer, offset) {
+                Ok(read) => return Ok(read),
+                Err(error) => {
+                    debug!(
+                        "couldn't read seed at offset {offset}, retrying in {delay:?} ({retry}/{}): {error}",
+                        self.retry_policy.retries
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+        read_full(source, buffer, offset)
+    }
+
+    /// Add newly found unreadable sectors to the bad-block map
+    fn record(&self, sectors: &[u64]) -> io::Result<()> {
+        let mut bad_sectors = self.lock();
+        let new_sectors: BTreeSet<u64> = sectors
+            .iter()
+            .copied()
+            .filter(|&sector| bad_sectors.insert(sector))
+            .collect();
+        if new_sectors.is_empty() {
+            return Ok(());
+        }
+        let lines = byte_ranges(&new_sectors).into_iter().fold(
+            String::new(),
+            |mut lines, (offset, len)| {
+                let _ = writeln!(lines, "{offset} {len}");
+                lines
+            },
+        );
+
+        fs::File::options()
+            .create(true)
+            .append(true)
+            .open(&self.bad_block_file)?
+            .write_all(lines.as_bytes())
+    }
+}
+
+/// Read until the buffer is full or the end of the source is reached
+fn read_full(source: &dyn Source, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match source.read_at(&mut buffer[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(len) => read += len,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read)
+}
+
+/// Merge sectors into byte ranges (offset and length)
+fn byte_ranges(sectors: &BTreeSet<u64>) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &sector in sectors {
+        match ranges.last_mut() {
+            Some((offset, len)) if *offset + *len == sector * SECTOR_SIZE => *len += SECTOR_SIZE,
+            _ => ranges.push((sector * SECTOR_SIZE, SECTOR_SIZE)),
+        }
+    }
+    ranges
+}
+
+fn parse_bad_blocks(contents: &str) -> io::Result<BTreeSet<u64>> {
+    let mut sectors = BTreeSet::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let range = line.split_once(' ').and_then(|(offset, len)| {
+            Some((offset.parse::<u64>().ok()?, len.parse::<u64>().ok()?))
+        });
+        let Some((offset, len)) = range else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid bad-block map line: {line}"),
+            ));
+        };
+        sectors.extend(offset / SECTOR_SIZE..(offset + len).div_ceil(SECTOR_SIZE));
+    }
+    Ok(sectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_path::TempPath;
+
+    /// Enough for a few cache blocks
+    const SECTORS: u64 = 512;
+
+    /// Data whose reads fail if they touch any of the `bad` sectors, logging every read
+    struct Faulty {
+        data: Vec<u8>,
+        bad: BTreeSet<u64>,
+        reads: Arc<Mutex<Vec<(u64, usize)>>>,
+    }
+
+    impl Faulty {
+        fn new(bad: &[u64]) -> Self {
+            Self {
+                data: data(),
+                bad: bad.iter().copied().collect(),
+                reads: Arc::default(),
+            }
+        }
+    }
+
+    impl Source for Faulty {
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.reads.lock().unwrap().push((offset, buffer.len()));
+            let start = usize::try_from(offset).unwrap().min(self.data.len());
+            let end = (start + buffer.len()).min(self.data.len());
+            if end > start
+                && self
+                    .bad
+                    .range(offset / SECTOR_SIZE..=(end as u64 - 1) / SECTOR_SIZE)
+                    .next()
+                    .is_some()
+            {
+                return Err(io::Error::other("bad sector"));
+            }
+            buffer[..end - start].copy_from_slice(&self.data[start..end]);
+            Ok(end - start)
+        }
+    }
+
+    fn data() -> Vec<u8> {
+        (0..SECTORS * SECTOR_SIZE)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect()
+    }
+
+    fn seed(source: Faulty, mirrors: Vec<Faulty>, bad_block_file: &Path) -> Seed {
+        Seed {
+            source: Box::new(source),
+            mirrors: mirrors
+                .into_iter()
+                .enumerate()
+                .map(|(i, source)| Mirror {
+                    path: PathBuf::from(format!("mirror{i}")),
+                    source: Box::new(source),
+                    served: Mutex::new(BTreeSet::new()),
+                })
+                .collect(),
+            retry_policy: RetryPolicy {
+                retries: 1,
+                delay: Duration::ZERO,
+            },
+            fail_unreadable: false,
+            bad_block_file: bad_block_file.to_path_buf(),
+            bad_sectors: Mutex::new(BTreeSet::new()),
+            cache: None,
+            offset: 0,
+            length: None,
+        }
+    }
+
+    /// `data()[offset..offset + len]` with the given sectors zeroed
+    fn expected(offset: u64, len: usize, zeroed: &[u64]) -> Vec<u8> {
+        let mut expected = data();
+        for &sector in zeroed {
+            let start = usize::try_from(sector * SECTOR_SIZE).unwrap();
+            let end = usize::try_from((sector + 1) * SECTOR_SIZE).unwrap();
+            expected[start..end].fill(0);
+        }
+        let start = usize::try_from(offset).unwrap();
+        expected[start..start + len].to_vec()
+    }
+
+    #[test]
+    fn unreadable_sectors_are_split_out_zeroed_and_recorded() {
+        let bad_block_file = TempPath::new("seed-split");
+        let source = Faulty::new(&[3, 5]);
+        let reads = source.reads.clone();
+        let seed = seed(source, Vec::new(), &bad_block_file);
+
+        // starts and ends partway through a sector
+        let (offset, len) = (100, 3000);
+        let mut buffer = vec![0xaa; len];
+        let error = seed.read_at(&mut buffer, offset).unwrap_err();
+        assert!(
+            error.to_string().contains("2 unreadable sectors"),
+            "{error}"
+        );
+        assert_eq!(buffer, expected(offset, len, &[3, 5]));
+        assert_eq!(
+            fs::read_to_string(&bad_block_file).unwrap(),
+            "1536 512\n2560 512\n"
+        );
+        assert_eq!(seed.bad_sectors(), 2);
+
+        // recorded sectors are zero-filled without being read again
+        reads.lock().unwrap().clear();
+        let mut buffer = vec![0xaa; len];
+        seed.read_at(&mut buffer, offset).unwrap_err();
+        assert_eq!(buffer, expected(offset, len, &[3, 5]));
+        for &(offset, len) in reads.lock().unwrap().iter() {
+            let sectors = offset / SECTOR_SIZE..=(offset + len as u64 - 1) / SECTOR_SIZE;
+            assert!(
+                !sectors.contains(&3) && !sectors.contains(&5),
+                "{offset} {len}"
+            );
+        }
+        assert_eq!(
+            fs::read_to_string(&bad_block_file).unwrap(),
+            "1536 512\n2560 512\n"
+        );
+    }
+
+    #[test]
+    fn reads_past_the_end_around_a_bad_sector_are_short() {
+        let bad_block_file = TempPath::new("seed-end");
+        let seed = seed(Faulty::new(&[SECTORS - 1]), Vec::new(), &bad_block_file);
+
+        let offset = (SECTORS - 2) * SECTOR_SIZE + 10;
+        let mut buffer = vec![0xaa; 2000];
+        seed.read_at(&mut buffer, offset).unwrap_err();
+        let len = usize::try_from(SECTORS * SECTOR_SIZE - offset).unwrap();
+        assert_eq!(buffer[..len], expected(offset, len, &[SECTORS - 1]));
+    }
+
+    #[test]
+    fn mirrors_fill_in_unreadable_sectors() {
+        let bad_block_file = TempPath::new("seed-mirrors");
+        let seed = seed(
+            Faulty::new(&[2, 6]),
+            vec![Faulty::new(&[2]), Faulty::new(&[])],
+            &bad_block_file,
+        );
+
+        let mut buffer = vec![0; 4000];
+        assert_eq!(seed.read_at(&mut buffer, 50).unwrap(), buffer.len());
+        assert_eq!(buffer, expected(50, buffer.len(), &[]));
+        let served = |mirror: &Mirror| mirror.served.lock().unwrap().clone();
+        assert_eq!(served(&seed.mirrors[0]), BTreeSet::from([6]));
+        assert_eq!(served(&seed.mirrors[1]), BTreeSet::from([2]));
+
+        // the seed's own bad sectors are still recorded, even though the mirrors had them
+        assert_eq!(
+            parse_bad_blocks(&fs::read_to_string(&bad_block_file).unwrap()).unwrap(),
+            BTreeSet::from([2, 6])
+        );
+    }
+
+    #[test]
+    fn cached_blocks_are_not_read_again() {
+        let bad_block_file = TempPath::new("seed-cache");
+        let source = Faulty::new(&[]);
+        let reads = source.reads.clone();
+        let seed = seed(source, Vec::new(), &bad_block_file).with_cache(SECTORS * SECTOR_SIZE, 0);
+        let block = cache::BLOCK_SIZE as u64;
+
+        let mut buffer = vec![0; cache::BLOCK_SIZE];
+        seed.read_at(&mut buffer, block).unwrap();
+        reads.lock().unwrap().clear();
+
+        let mut buffer = vec![0; 3 * cache::BLOCK_SIZE - 100];
+        assert_eq!(seed.read_at(&mut buffer, 100).unwrap(), buffer.len());
+        assert_eq!(buffer, expected(100, buffer.len(), &[]));
+        assert_eq!(
+            *reads.lock().unwrap(),
+            [(0, cache::BLOCK_SIZE), (2 * block, cache::BLOCK_SIZE)]
+        );
+    }
+
+    #[test]
+    fn bad_block_map_round_trips() {
+        let sectors = BTreeSet::from([0, 1, 2, 7, 9, 10]);
+        let ranges = byte_ranges(&sectors);
+        assert_eq!(ranges, [(0, 1536), (3584, 512), (4608, 1024)]);
+
+        let contents = "0 1536\n3584 512\n4608 1024\n";
+        assert_eq!(parse_bad_blocks(contents).unwrap(), sectors);
+
+        // ranges written by hand cover every sector they touch
+        assert_eq!(
+            parse_bad_blocks("100 1000\n\n").unwrap(),
+            BTreeSet::from([0, 1, 2])
+        );
+        let error = parse_bad_blocks("100\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,500 @@
+use super::Source;
+use log::debug;
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    sync::{Mutex, PoisonError},
+};
+
+const DEFAULT_PORT: u16 = 10809;
+/// Largest read sent in a single request (servers may refuse much larger ones)
+const MAX_READ: usize = 1 << 20;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_ERROR: u32 = 1 << 31;
+const INFO_EXPORT: u16 = 0;
+const CMD_READ: u16 = 0;
+const CMD_DISC: u16 = 2;
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    handle: u64,
+}
+
+/// Where the server listens
+enum Server {
+    Unix(String),
+    Tcp(String, u16),
+}
+
+/// Export of an NBD server (e.g. qemu-nbd or nbdkit), read with a built-in client
+pub struct Nbd {
+    uri: String,
+    server: Server,
+    export: String,
+    /// `None` after a failure left the connection out of sync, until it's reconnected
+    connection: Mutex<Option<Connection>>,
+    len: u64,
+}
+
+impl Nbd {
+    /// Connect to an `nbd://host[:port][/export]` or `nbd+unix:///export?socket=path` URI
+    pub fn open(uri: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid NBD URI {uri}"),
+            )
+        };
+
+        let (server, export) = if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            let (export, socket) = rest.split_once("?socket=").ok_or_else(invalid)?;
+            (Server::Unix(socket.to_string()), export)
+        } else if let Some(rest) = uri.strip_prefix("nbd://") {
+            let (authority, export) = rest.split_once('/').unwrap_or((rest, ""));
+            let (host, port) = address(authority).ok_or_else(invalid)?;
+            (Server::Tcp(host.to_string(), port), export)
+        } else {
+            return Err(invalid());
+        };
+        let export = export.strip_prefix('/').unwrap_or(export);
+
+        let mut nbd = Self {
+            uri: uri.to_string(),
+            server,
+            export: export.to_string(),
+            connection: Mutex::new(None),
+            len: 0,
+        };
+        let (connection, len) = nbd.connect()?;
+        debug!("connected to {uri} ({len} bytes)");
+        nbd.connection = Mutex::new(Some(connection));
+        nbd.len = len;
+        Ok(nbd)
+    }
+
+    /// Open a new connection and negotiate the export, returning its size
+    fn connect(&self) -> io::Result<(Connection, u64)> {
+        let mut stream: Box<dyn Stream> = match &self.server {
+            Server::Unix(socket) => Box::new(UnixStream::connect(socket)?),
+            Server::Tcp(host, port) => Box::new(TcpStream::connect((host.as_str(), *port))?),
+        };
+        let len = handshake(&mut stream, &self.export)?;
+        Ok((Connection { stream, handle: 0 }, len))
+    }
+
+    /// Reconnect after a failure broke the previous connection
+    fn reconnect(&self) -> io::Result<Connection> {
+        debug!("reconnecting to {}", self.uri);
+        let (connection, len) = self.connect()?;
+        if len != self.len {
+            return Err(io::Error::other(format!(
+                "NBD export {} changed size from {} to {len} bytes",
+                self.uri, self.len
+            )));
+        }
+        Ok(connection)
+    }
+}
+
+/// Host and port of a URI authority (`host`, `host:port`, `[ipv6]` or `[ipv6]:port`), with the
+/// default NBD port if there's none
+fn address(authority: &str) -> Option<(&str, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':')?)),
+        }
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => DEFAULT_PORT,
+    };
+    Some((host, port))
+}
+
+/// Negotiate the export with `NBD_OPT_GO`, returning its size
+fn handshake(stream: &mut Box<dyn Stream>, export: &str) -> io::Result<u64> {
+    let protocol_error = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    if read_u64(stream)? != NBDMAGIC || read_u64(stream)? != IHAVEOPT {
+        return Err(protocol_error("not a newstyle NBD server"));
+    }
+    let server_flags = read_u16(stream)?;
+    if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(protocol_error(
+            "NBD server doesn't support fixed newstyle negotiation",
+        ));
+    }
+    let client_flags = u32::from(server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES));
+    stream.write_all(&client_flags.to_be_bytes())?;
+
+    let name_len = u32::try_from(export.len()).map_err(io::Error::other)?;
+    let mut option = Vec::with_capacity(22 + export.len());
+    option.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    option.extend_from_slice(&OPT_GO.to_be_bytes());
+    option.extend_from_slice(&(name_len + 6).to_be_bytes());
+    option.extend_from_slice(&name_len.to_be_bytes());
+    option.extend_from_slice(export.as_bytes());
+    option.extend_from_slice(&0u16.to_be_bytes());
+    stream.write_all(&option)?;
+
+    let mut len = None;
+    loop {
+        if read_u64(stream)? != REPLY_MAGIC || read_u32(stream)? != OPT_GO {
+            return Err(protocol_error("unexpected NBD option reply"));
+        }
+        let reply = read_u32(stream)?;
+        let mut data = vec![0; read_u32(stream)? as usize];
+        stream.read_exact(&mut data)?;
+
+        match reply {
+            REP_ACK => break,
+            REP_INFO if data.len() >= 12 && data[..2] == INFO_EXPORT.to_be_bytes() => {
+                len = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()));
+            }
+            REP_INFO => {}
+            _ if reply & REP_ERROR != 0 => {
+                return Err(io::Error::other(format!(
+                    "NBD server refused export {export:?}: {}",
+                    String::from_utf8_lossy(&data)
+                )));
+            }
+            _ => return Err(protocol_error("unexpected NBD option reply")),
+        }
+    }
+    len.ok_or_else(|| protocol_error("NBD server didn't report the export size"))
+}
+
+impl Connection {
+    fn send(&mut self, command: u16, offset: u64, len: u32) -> io::Result<u64> {
+        self.handle += 1;
+        let mut request = Vec::with_capacity(28);
+        request.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&command.to_be_bytes());
+        request.extend_from_slice(&self.handle.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&len.to_be_bytes());
+        self.stream.write_all(&request)?;
+        Ok(self.handle)
+    }
+
+    /// Read from the export, failing with the server's error if it refused the read
+    fn read(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<Result<(), io::Error>> {
+        let handle = self.send(
+            CMD_READ,
+            offset,
+            u32::try_from(buffer.len()).map_err(io::Error::other)?,
+        )?;
+        if read_u32(&mut self.stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected NBD reply",
+            ));
+        }
+        let error = read_u32(&mut self.stream)?;
+        if read_u64(&mut self.stream)? != handle {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "NBD reply for the wrong request",
+            ));
+        }
+        if error != 0 {
+            // NBD error values are errno values, and no data follows them
+            return Ok(Err(io::Error::from_raw_os_error(
+                i32::try_from(error).map_err(io::Error::other)?,
+            )));
+        }
+        self.stream.read_exact(buffer)?;
+        Ok(Ok(()))
+    }
+}
+
+impl Source for Nbd {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = usize::try_from(self.len.saturating_sub(offset))
+            .map_or(buffer.len(), |left| left.min(buffer.len()));
+
+        let mut guard = self
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut done = 0;
+        while done < len {
+            let connection = match guard.as_mut() {
+                Some(connection) => connection,
+                None => guard.insert(self.reconnect()?),
+            };
+            let chunk = (len - done).min(MAX_READ);
+            match connection.read(&mut buffer[done..done + chunk], offset + done as u64) {
+                Ok(result) => result?,
+                Err(error) => {
+                    // part of a request or reply may be left on the connection
+                    *guard = None;
+                    return Err(error);
+                }
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for Nbd {
+    fn drop(&mut self) {
+        if let Some(connection) = self
+            .connection
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            let _ = connection.send(CMD_DISC, 0, 0);
+        }
+    }
+}
+
+fn read_u16(stream: &mut Box<dyn Stream>) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    stream.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(stream: &mut Box<dyn Stream>) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(stream: &mut Box<dyn Stream>) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread,
+    };
+
+    const EIO: u32 = 5;
+
+    /// How the fake server misbehaves
+    #[derive(Clone, Copy)]
+    enum Fault {
+        None,
+        /// Answer reads from this offset with an EIO error reply
+        ErrorAt(u64),
+        /// Close the first connection halfway through the data of its first read reply
+        DropFirstRead,
+    }
+
+    fn read_exact<const N: usize>(stream: &mut TcpStream) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        stream.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Negotiate export `name` of `len` bytes with a client, returning whether it asked for it
+    fn serve_handshake(stream: &mut TcpStream, name: &str, len: u64) -> io::Result<bool> {
+        stream.write_all(&NBDMAGIC.to_be_bytes())?;
+        stream.write_all(&IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+        read_exact::<4>(stream)?;
+
+        assert_eq!(u64::from_be_bytes(read_exact(stream)?), IHAVEOPT);
+        assert_eq!(u32::from_be_bytes(read_exact(stream)?), OPT_GO);
+        let mut option = vec![0; u32::from_be_bytes(read_exact(stream)?) as usize];
+        stream.read_exact(&mut option)?;
+        let name_len = u32::from_be_bytes(option[..4].try_into().unwrap()) as usize;
+        let requested = &option[4..4 + name_len] == name.as_bytes();
+
+        let reply = |stream: &mut TcpStream, kind: u32, data: &[u8]| {
+            stream.write_all(&REPLY_MAGIC.to_be_bytes())?;
+            stream.write_all(&OPT_GO.to_be_bytes())?;
+            stream.write_all(&kind.to_be_bytes())?;
+            stream.write_all(&u32::try_from(data.len()).unwrap().to_be_bytes())?;
+            stream.write_all(data)
+        };
+        if !requested {
+            reply(stream, REP_ERROR | 1, b"unknown export")?;
+            return Ok(false);
+        }
+        let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+        info.extend_from_slice(&len.to_be_bytes());
+        info.extend_from_slice(&0u16.to_be_bytes());
+        reply(stream, REP_INFO, &info)?;
+        reply(stream, REP_ACK, &[])?;
+        Ok(true)
+    }
+
+    fn serve_connection(
+        mut stream: TcpStream,
+        data: &[u8],
+        fault: Fault,
+        first: bool,
+        reads: &AtomicUsize,
+    ) -> io::Result<()> {
+        if !serve_handshake(&mut stream, "disk", data.len() as u64)? {
+            return Ok(());
+        }
+        loop {
+            assert_eq!(u32::from_be_bytes(read_exact(&mut stream)?), REQUEST_MAGIC);
+            read_exact::<2>(&mut stream)?;
+            let command = u16::from_be_bytes(read_exact(&mut stream)?);
+            let handle: [u8; 8] = read_exact(&mut stream)?;
+            let offset = u64::from_be_bytes(read_exact(&mut stream)?);
+            let len = u64::from(u32::from_be_bytes(read_exact(&mut stream)?));
+            if command == CMD_DISC {
+                return Ok(());
+            }
+            assert_eq!(command, CMD_READ);
+            reads.fetch_add(1, Ordering::SeqCst);
+
+            let error = matches!(fault, Fault::ErrorAt(at) if (offset..offset + len).contains(&at));
+            stream.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+            stream.write_all(&(if error { EIO } else { 0 }).to_be_bytes())?;
+            stream.write_all(&handle)?;
+            let range = usize::try_from(offset).unwrap()..usize::try_from(offset + len).unwrap();
+            if matches!(fault, Fault::DropFirstRead) && first {
+                return stream.write_all(&data[range.start..range.start + range.len() / 2]);
+            }
+            if !error {
+                stream.write_all(&data[range])?;
+            }
+        }
+    }
+
+    /// Serve `data` as export `disk` on a local port, returning its URI with counts of the
+    /// connections and reads made
+    fn serve(data: Vec<u8>, fault: Fault) -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("nbd://{}/disk", listener.local_addr().unwrap());
+        let (connections, reads) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (connection_count, read_count) = (Arc::clone(&connections), Arc::clone(&reads));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let first = connection_count.fetch_add(1, Ordering::SeqCst) == 0;
+                let _ = serve_connection(stream.unwrap(), &data, fault, first, &read_count);
+            }
+        });
+        (uri, connections, reads)
+    }
+
+    fn image() -> Vec<u8> {
+        (0..3 * MAX_READ + 1000)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect()
+    }
+
+    fn read(nbd: &Nbd, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; len];
+        let read = nbd.read_at(&mut buffer, offset as u64)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    #[test]
+    fn handshake_and_reads() {
+        let data = image();
+        let (uri, connections, reads) = serve(data.clone(), Fault::None);
+        let nbd = Nbd::open(&uri).unwrap();
+        assert_eq!(nbd.len().unwrap(), data.len() as u64);
+        assert_eq!(read(&nbd, 10, 100).unwrap(), data[10..110]);
+        // large reads are split into requests of at most MAX_READ bytes
+        assert_eq!(read(&nbd, 5, data.len()).unwrap(), data[5..]);
+        assert_eq!(reads.load(Ordering::SeqCst), 1 + 4);
+        assert!(read(&nbd, data.len(), 10).unwrap().is_empty());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unknown_export_is_refused() {
+        let (uri, _, _) = serve(image(), Fault::None);
+        let uri = uri.replace("/disk", "/other");
+        assert!(Nbd::open(&uri).is_err());
+    }
+
+    #[test]
+    fn error_replies_keep_the_connection() {
+        let data = image();
+        let (uri, connections, _) = serve(data.clone(), Fault::ErrorAt(5000));
+        let nbd = Nbd::open(&uri).unwrap();
+        let error = read(&nbd, 4096, 4096).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(5));
+        assert_eq!(read(&nbd, 0, 4096).unwrap(), data[..4096]);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn broken_connection_is_reopened() {
+        let data = image();
+        let (uri, connections, _) = serve(data.clone(), Fault::DropFirstRead);
+        let nbd = Nbd::open(&uri).unwrap();
+        assert!(read(&nbd, 0, 4096).is_err());
+        assert_eq!(read(&nbd, 100, 4096).unwrap(), data[100..4196]);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(address("example.com"), Some(("example.com", DEFAULT_PORT)));
+        assert_eq!(address("example.com:1234"), Some(("example.com", 1234)));
+        assert_eq!(address("127.0.0.1"), Some(("127.0.0.1", DEFAULT_PORT)));
+        assert_eq!(address("[::1]"), Some(("::1", DEFAULT_PORT)));
+        assert_eq!(address("[::1]:1234"), Some(("::1", 1234)));
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        for authority in [
+            "",
+            ":1234",
+            "[]",
+            "[::1",
+            "[::1]1234",
+            "host:port",
+            "host:70000",
+            "::1",
+        ] {
+            assert_eq!(address(authority), None, "{authority}");
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use super::Source;
+use log::debug;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Image split into segments (`image.001`, `image.002`, ... or `image.aa`, `image.ab`, ...), read
+/// as one
+pub struct Split {
+    /// Segments with the offset each one starts at
+    segments: Vec<(u64, Box<dyn Source>)>,
+    len: u64,
+}
+
+impl Split {
+    pub fn open(
+        paths: &[PathBuf],
+        open: impl Fn(&Path) -> io::Result<Box<dyn Source>>,
+    ) -> io::Result<Self> {
+        let mut segments = Vec::with_capacity(paths.len());
+        let mut len = 0;
+        for path in paths {
+            let with_path = |error: io::Error| {
+                io::Error::new(error.kind(), format!("{}: {error}", path.display()))
+            };
+            let segment = open(path).map_err(with_path)?;
+            let segment_len = segment.len().map_err(with_path)?;
+            debug!(
+                "segment {} starts at offset {len} ({segment_len} bytes)",
+                path.display()
+            );
+            segments.push((len, segment));
+            len += segment_len;
+        }
+        Ok(Self { segments, len })
+    }
+}
+
+impl Source for Split {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = usize::try_from(self.len.saturating_sub(offset))
+            .map_or(buffer.len(), |left| left.min(buffer.len()));
+
+        // the last segment starting at or before the offset (empty segments are skipped over)
+        let mut index = self
+            .segments
+            .partition_point(|(start, _)| *start <= offset)
+            .saturating_sub(1);
+        let mut done = 0;
+        while done < len {
+            let (start, segment) = &self.segments[index];
+            let position = offset + done as u64 - start;
+            let end = self
+                .segments
+                .get(index + 1)
+                .map_or(self.len, |(next, _)| *next);
+            let chunk = usize::try_from(end - start - position)
+                .map_or(len - done, |left| left.min(len - done));
+            if super::read_full(segment.as_ref(), &mut buffer[done..done + chunk], position)?
+                < chunk
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("seed segment {} shrank after it was opened", index + 1),
+                ));
+            }
+            done += chunk;
+            index += 1;
+        }
+        Ok(len)
+    }
+
+    fn segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    fn is_local(&self) -> bool {
+        self.segments.iter().all(|(_, segment)| segment.is_local())
+    }
+}
+
+/// Segments `location` refers to: the files matching it if it's a glob pattern, or itself and the
+/// following segments if it's the first one of a recognised naming scheme (`image.000` or
+/// `image.001` with at least three digits, or `image.aa` like split(1) names them)
+pub fn segment_paths(location: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+    let Some(pattern) = location.to_str() else {
+        return Ok(None);
+    };
+    if !location.exists() && pattern.contains(['*', '?', '[']) {
+        let paths = glob::glob(pattern)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io::Error::from)?;
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no seed segments match {pattern}"),
+            ));
+        }
+        return Ok(Some(paths));
+    }
+
+    let Some((stem, first)) = pattern.rsplit_once('.') else {
+        return Ok(None);
+    };
+    if !is_first_segment(first) {
+        return Ok(None);
+    }
+    let mut paths = Vec::new();
+    let mut suffix = Some(first.to_string());
+    while let Some(current) = suffix {
+        let path = PathBuf::from(format!("{stem}.{current}"));
+        if !path.exists() {
+            break;
+        }
+        paths.push(path);
+        suffix = next_suffix(&current);
+    }
+    Ok((paths.len() > 1).then_some(paths))
+}
+
+/// Whether `suffix` starts a sequence of segments: `000` or `001` (zero-padded to at least three
+/// digits, so an unrelated `image.1` isn't joined with `image.2`), or `aa`
+fn is_first_segment(suffix: &str) -> bool {
+    let numbered = suffix.len() >= 3
+        && suffix.bytes().all(|byte| byte.is_ascii_digit())
+        && suffix.trim_start_matches('0').len() <= 1
+        && suffix.ends_with(['0', '1']);
+    let lettered = suffix.len() >= 2 && suffix.bytes().all(|byte| byte == b'a');
+    numbered || lettered
+}
+
+/// Suffix of the segment following the one with `suffix`, keeping its width (`009` -> `010`,
+/// `az` -> `ba`), or `None` once the width is exhausted
+fn next_suffix(suffix: &str) -> Option<String> {
+    let (first, last) = if suffix.bytes().all(|byte| byte.is_ascii_digit()) {
+        (b'0', b'9')
+    } else {
+        (b'a', b'z')
+    };
+    let mut bytes = suffix.as_bytes().to_vec();
+    for byte in bytes.iter_mut().rev() {
+        if *byte < last {
+            *byte += 1;
+            return String::from_utf8(bytes).ok();
+        }
+        *byte = first;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_path::TempPath;
+
+    #[test]
+    fn first_segments() {
+        for suffix in ["000", "001", "0001", "aa", "aaa"] {
+            assert!(is_first_segment(suffix), "{suffix}");
+        }
+        for suffix in ["1", "01", "002", "010", "100", "ab", "a", "img", "a1"] {
+            assert!(!is_first_segment(suffix), "{suffix}");
+        }
+    }
+
+    #[test]
+    fn next_suffixes() {
+        assert_eq!(next_suffix("001").as_deref(), Some("002"));
+        assert_eq!(next_suffix("009").as_deref(), Some("010"));
+        assert_eq!(next_suffix("999"), None);
+        assert_eq!(next_suffix("aa").as_deref(), Some("ab"));
+        assert_eq!(next_suffix("az").as_deref(), Some("ba"));
+        assert_eq!(next_suffix("zz"), None);
+    }
+
+    #[test]
+    fn numbered_files_need_a_recognised_scheme() {
+        let directory = TempPath::new("split");
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in [
+            "log.1",
+            "log.2",
+            "image.001",
+            "image.002",
+            "image.003",
+            "disk.aa",
+            "disk.ab",
+        ] {
+            std::fs::write(directory.join(name), name).unwrap();
+        }
+
+        assert_eq!(segment_paths(&directory.join("log.1")).unwrap(), None);
+        let segments = |name: &str| {
+            segment_paths(&directory.join(name))
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            segments("image.001"),
+            ["image.001", "image.002", "image.003"]
+        );
+        assert_eq!(segments("disk.aa"), ["disk.aa", "disk.ab"]);
+    }
+}
@@ -0,0 +1,513 @@
+use super::{Granular, Storage};
+use std::{
+    fs, io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+const HEADER_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 16;
+
+fn entry_offset(index: u64) -> u64 {
+    HEADER_SIZE as u64 + index * ENTRY_SIZE as u64
+}
+
+/// Where a granule's compressed data lives in the underlying storage
+///
+/// `len == 0` means the granule reads as zeroes, and `len == granule` means it's stored
+/// uncompressed because compression didn't make it smaller.
+#[derive(Clone, Copy, Default)]
+struct Entry {
+    offset: u64,
+    len: u32,
+    capacity: u32,
+}
+
+impl Entry {
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[..8].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.len.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.capacity.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            offset: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            capacity: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// End of the data written so far, the logical length and the granule size, stored at the
+/// start of the index
+pub(super) struct Header {
+    next: u64,
+    len: u64,
+    granule: u32,
+    /// Unused ranges before `next` as (offset, len), sorted and merged (not stored, rebuilt from
+    /// the index when opened)
+    free: Vec<(u64, u64)>,
+}
+
+impl Header {
+    /// Take `len` bytes from the first free range that fits, or from the end of the data
+    fn allocate(&mut self, len: u64) -> u64 {
+        if let Some(position) = self.free.iter().position(|&(_, free)| free >= len) {
+            let (offset, free) = self.free[position];
+            if free == len {
+                self.free.remove(position);
+            } else {
+                self.free[position] = (offset + len, free - len);
+            }
+            return offset;
+        }
+        let offset = self.next;
+        self.next += len;
+        offset
+    }
+
+    /// Return a range to the free list, merging it with its neighbours
+    fn release(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let position = self.free.partition_point(|&(free, _)| free < offset);
+        self.free.insert(position, (offset, len));
+        if position + 1 < self.free.len() {
+            let (next, next_len) = self.free[position + 1];
+            if offset + len == next {
+                self.free[position].1 += next_len;
+                self.free.remove(position + 1);
+            }
+        }
+        if position > 0 {
+            let (previous, previous_len) = self.free[position - 1];
+            if previous + previous_len == offset {
+                self.free[position - 1].1 += self.free[position].1;
+                self.free.remove(position);
+            }
+        }
+        // space at the end goes back to being appended to
+        if let Some(&(last, last_len)) = self.free.last()
+            && last + last_len == self.next
+        {
+            self.next = last;
+            self.free.pop();
+        }
+    }
+}
+
+/// Storage that keeps each granule zstd-compressed, with an index file mapping granules to data
+///
+/// Compressed data is rewritten in place when the new data fits in the space the granule already
+/// had, and otherwise goes to the first free range that fits (space released by granules that
+/// were cleared or moved) or is appended to the underlying storage.
+pub struct Compressed {
+    inner: Box<dyn Storage>,
+    index: fs::File,
+    granule: u32,
+    header: Mutex<Header>,
+}
+
+impl Compressed {
+    pub fn open(
+        inner: Box<dyn Storage>,
+        index_file: &Path,
+        granule: u32,
+        create: bool,
+    ) -> io::Result<Self> {
+        let index = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(index_file)?;
+
+        let mut bytes = [0; HEADER_SIZE];
+        let header = if index.read_at(&mut bytes, 0)? == bytes.len() {
+            Header {
+                next: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+                len: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+                granule: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+                free: Vec::new(),
+            }
+        } else {
+            Header {
+                next: 0,
+                len: 0,
+                granule,
+                free: Vec::new(),
+            }
+        };
+        if header.granule == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid compression index header",
+            ));
+        }
+
+        let compressed = Self {
+            inner,
+            index,
+            granule: header.granule,
+            header: Mutex::new(header),
+        };
+        let mut header = compressed.lock();
+        header.free = compressed.free_ranges(header.next)?;
+        compressed.write_header(&header)?;
+        drop(header);
+        Ok(compressed)
+    }
+
+    /// Ranges before `next` that no granule uses
+    fn free_ranges(&self, next: u64) -> io::Result<Vec<(u64, u64)>> {
+        let mut used = Vec::new();
+        self.for_each_entry(|entry| {
+            if entry.capacity > 0 {
+                used.push((entry.offset, u64::from(entry.capacity)));
+            }
+        })?;
+        used.sort_unstable();
+
+        let mut free = Vec::new();
+        let mut position = 0;
+        for (offset, capacity) in used {
+            if offset > position {
+                free.push((position, offset - position));
+            }
+            position = position.max(offset + capacity);
+        }
+        if next > position {
+            free.push((position, next - position));
+        }
+        Ok(free)
+    }
+
+    fn for_each_entry(&self, mut f: impl FnMut(Entry)) -> io::Result<()> {
+        let mut buffer = vec![0; 4096 * ENTRY_SIZE];
+        let mut offset = entry_offset(0);
+        loop {
+            let read = self.index.read_at(&mut buffer, offset)?;
+            if read == 0 {
+                return Ok(());
+            }
+            buffer[..read]
+                .chunks_exact(ENTRY_SIZE)
+                .for_each(|bytes| f(Entry::from_bytes(bytes)));
+            offset += read as u64;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Header> {
+        self.header
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn write_header(&self, header: &Header) -> io::Result<()> {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..8].copy_from_slice(&header.next.to_le_bytes());
+        bytes[8..16].copy_from_slice(&header.len.to_le_bytes());
+        bytes[16..20].copy_from_slice(&header.granule.to_le_bytes());
+        self.index.write_all_at(&bytes, 0)
+    }
+
+    fn entry(&self, index: u64) -> io::Result<Entry> {
+        let mut bytes = [0; ENTRY_SIZE];
+        if self.index.read_at(&mut bytes, entry_offset(index))? < bytes.len() {
+            return Ok(Entry::default());
+        }
+        Ok(Entry::from_bytes(&bytes))
+    }
+
+    fn set_entry(&self, index: u64, entry: Entry) -> io::Result<()> {
+        self.index
+            .write_all_at(&entry.to_bytes(), entry_offset(index))
+    }
+
+    fn clear_entry(&self, header: &mut Header, index: u64, entry: Entry) -> io::Result<()> {
+        if entry.len == 0 {
+            return Ok(());
+        }
+        self.set_entry(index, Entry::default())?;
+        self.release(header, entry)
+    }
+
+    fn release(&self, header: &mut Header, entry: Entry) -> io::Result<()> {
+        if entry.capacity > 0 {
+            self.inner.punch_hole(entry.offset, entry.capacity.into())?;
+            header.release(entry.offset, entry.capacity.into());
+        }
+        self.write_header(header)
+    }
+}
+
+impl Granular for Compressed {
+    type State = Header;
+
+    fn granule(&self) -> u32 {
+        self.granule
+    }
+
+    fn read_granule(&self, _: &mut Header, index: u64) -> io::Result<Option<Vec<u8>>> {
+        let entry = self.entry(index)?;
+        if entry.len == 0 {
+            return Ok(Some(vec![0; self.granule as usize]));
+        }
+
+        let mut data = vec![0; entry.len as usize];
+        let mut read = 0;
+        while read < data.len() {
+            match self
+                .inner
+                .read_at(&mut data[read..], entry.offset + read as u64)?
+            {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("compressed data of block {index} is truncated"),
+                    ));
+                }
+                len => read += len,
+            }
+        }
+        if entry.len == self.granule {
+            return Ok(Some(data));
+        }
+
+        let plaintext = zstd::bulk::decompress(&data, self.granule as usize)?;
+        if plaintext.len() != self.granule as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("compressed data of block {index} has the wrong size"),
+            ));
+        }
+        Ok(Some(plaintext))
+    }
+
+    fn write_granule(&self, header: &mut Header, index: u64, plaintext: &[u8]) -> io::Result<()> {
+        let mut entry = self.entry(index)?;
+        if plaintext.iter().all(|&byte| byte == 0) {
+            return self.clear_entry(header, index, entry);
+        }
+
+        let mut data = zstd::bulk::compress(plaintext, 0)?;
+        if data.len() >= plaintext.len() {
+            data = plaintext.to_vec();
+        }
+        let len = u32::try_from(data.len()).map_err(io::Error::other)?;
+
+        if len <= entry.capacity {
+            entry.len = len;
+            self.inner.write_all_at(&data, entry.offset)?;
+            return self.set_entry(index, entry);
+        }
+
+        // the old range is only released once the entry points elsewhere, so it's never
+        // overwritten while it's still in use
+        let old = entry;
+        entry.offset = header.allocate(len.into());
+        entry.len = len;
+        entry.capacity = len;
+        self.inner.write_all_at(&data, entry.offset)?;
+        self.set_entry(index, entry)?;
+        self.release(header, old)
+    }
+
+    fn clear_granule(&self, header: &mut Header, index: u64) -> io::Result<()> {
+        self.clear_entry(header, index, self.entry(index)?)
+    }
+}
+
+impl Storage for Compressed {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.lock().len)
+    }
+
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.inner.metadata()
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut header = self.lock();
+        let len = usize::try_from(header.len.saturating_sub(offset))
+            .map_or(buffer.len(), |left| left.min(buffer.len()));
+        self.read_granules(&mut header, &mut buffer[..len], offset)
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        let mut header = self.lock();
+        self.write_granules(&mut header, buffer, offset)?;
+
+        let end = offset + buffer.len() as u64;
+        if end > header.len {
+            header.len = end;
+            self.write_header(&header)?;
+        }
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut header = self.lock();
+        let len = len.min(header.len.saturating_sub(offset));
+        self.zero_granules(&mut header, offset, len)
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        let mut header = self.lock();
+        if len > header.len {
+            header.len = len;
+            self.write_header(&header)?;
+        }
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut header = self.lock();
+        if len < header.len {
+            let old_len = header.len;
+            self.zero_granules(&mut header, len, old_len - len)?;
+            self.index
+                .set_len(entry_offset(len.div_ceil(self.granule_size())))?;
+        }
+        header.len = len;
+        self.write_header(&header)
+    }
+
+    fn compression(&self) -> io::Result<Option<(u64, u64)>> {
+        let (mut uncompressed, mut compressed) = (0, 0);
+        self.for_each_entry(|entry| {
+            if entry.len > 0 {
+                uncompressed += u64::from(self.granule);
+                compressed += u64::from(entry.len);
+            }
+        })?;
+        Ok(Some((uncompressed, compressed)))
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()?;
+        self.index.sync_data()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.inner.sync_all()?;
+        self.index.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::file::FileStorage, temp_path::TempPath};
+
+    const GRANULE: u32 = 4096;
+
+    fn open(path: &TempPath) -> Compressed {
+        Compressed::open(
+            Box::new(FileStorage::file(path.open())),
+            &crate::with_suffix(path, ".index"),
+            GRANULE,
+            true,
+        )
+        .unwrap()
+    }
+
+    fn data_len(path: &TempPath) -> u64 {
+        fs::metadata(path).unwrap().len()
+    }
+
+    /// Data that doesn't compress, different for every seed
+    fn incompressible(seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..GRANULE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    fn read_granule(storage: &Compressed, index: u64) -> Vec<u8> {
+        let mut buffer = vec![0; GRANULE as usize];
+        storage
+            .read_at(&mut buffer, index * u64::from(GRANULE))
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn repeated_rewrites_reuse_space() {
+        let path = TempPath::new("compressed-rewrites");
+        let storage = open(&path);
+        let granule = u64::from(GRANULE);
+        for round in 0..50 {
+            // growing from a small compressed granule to an incompressible one moves it
+            for index in 0..2 {
+                storage
+                    .write_all_at(&[1; GRANULE as usize], index * granule)
+                    .unwrap();
+            }
+            storage.punch_hole(0, 2 * granule).unwrap();
+            for index in 0..2 {
+                storage
+                    .write_all_at(&incompressible(round * 2 + index), index * granule)
+                    .unwrap();
+            }
+        }
+
+        assert!(data_len(&path) <= 3 * granule, "{}", data_len(&path));
+        assert_eq!(read_granule(&storage, 0), incompressible(98));
+        assert_eq!(read_granule(&storage, 1), incompressible(99));
+    }
+
+    #[test]
+    fn free_space_is_found_after_reopening() {
+        let path = TempPath::new("compressed-reopen");
+        let granule = u64::from(GRANULE);
+        let storage = open(&path);
+        for index in 0..3 {
+            storage
+                .write_all_at(&incompressible(index), index * granule)
+                .unwrap();
+        }
+        storage.punch_hole(granule, granule).unwrap();
+        drop(storage);
+        let len = data_len(&path);
+
+        let storage = open(&path);
+        storage
+            .write_all_at(&incompressible(10), 5 * granule)
+            .unwrap();
+        assert_eq!(data_len(&path), len);
+        assert_eq!(read_granule(&storage, 0), incompressible(0));
+        assert_eq!(read_granule(&storage, 1), vec![0; GRANULE as usize]);
+        assert_eq!(read_granule(&storage, 2), incompressible(2));
+        assert_eq!(read_granule(&storage, 5), incompressible(10));
+    }
+
+    #[test]
+    fn released_ranges_merge() {
+        let mut header = Header {
+            next: 40,
+            len: 0,
+            granule: 1,
+            free: Vec::new(),
+        };
+        header.release(10, 10);
+        header.release(0, 5);
+        header.release(5, 5);
+        assert_eq!(header.free, [(0, 20)]);
+        header.release(30, 10);
+        assert_eq!((header.next, &header.free[..]), (30, &[(0, 20)][..]));
+        assert_eq!(header.allocate(15), 0);
+        assert_eq!(header.allocate(10), 30);
+        assert_eq!((header.next, &header.free[..]), (40, &[(15, 5)][..]));
+    }
+}
@@ -0,0 +1,433 @@
+use super::{Granular, Storage};
+use crate::checksum;
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+const HEADER_SIZE: usize = 16;
+const MAP_ENTRY_SIZE: usize = 8;
+const BLOCK_ENTRY_SIZE: usize = 16;
+
+fn map_offset(index: u64) -> u64 {
+    HEADER_SIZE as u64 + index * MAP_ENTRY_SIZE as u64
+}
+
+fn block_offset(slot: u64) -> u64 {
+    slot * BLOCK_ENTRY_SIZE as u64
+}
+
+pub(super) struct State {
+    len: u64,
+    granule: u32,
+
+    /// Slots of the unique blocks with each content hash
+    slots_by_hash: HashMap<u64, Vec<u64>>,
+    free_slots: Vec<u64>,
+    slot_count: u64,
+}
+
+/// Storage that keeps each unique granule once, shared by every offset that holds it
+///
+/// The map file holds the logical length and granule size followed by one slot number (plus one,
+/// 0 meaning zeroes) per granule. The block file holds the content hash and reference count of
+/// every slot, and slot data is stored at `slot * granule` in the underlying storage.
+pub struct Deduplicated {
+    inner: Box<dyn Storage>,
+    map: fs::File,
+    blocks: fs::File,
+    granule: u32,
+    state: Mutex<State>,
+}
+
+impl Deduplicated {
+    pub fn open(
+        inner: Box<dyn Storage>,
+        map_file: &Path,
+        block_file: &Path,
+        granule: u32,
+        create: bool,
+    ) -> io::Result<Self> {
+        let open = |path| {
+            fs::File::options()
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false)
+                .open(path)
+        };
+        let (map, blocks) = (open(map_file)?, open(block_file)?);
+
+        let mut header = [0; HEADER_SIZE];
+        let (len, granule) = if map.read_at(&mut header, 0)? == header.len() {
+            (
+                u64::from_le_bytes(header[..8].try_into().unwrap()),
+                u32::from_le_bytes(header[8..12].try_into().unwrap()),
+            )
+        } else {
+            (0, granule)
+        };
+        if granule == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid deduplication map header",
+            ));
+        }
+
+        let mut state = State {
+            len,
+            granule,
+            slots_by_hash: HashMap::new(),
+            free_slots: Vec::new(),
+            slot_count: 0,
+        };
+        let mut buffer = vec![0; 4096 * BLOCK_ENTRY_SIZE];
+        loop {
+            let read = blocks.read_at(&mut buffer, block_offset(state.slot_count))?;
+            if read == 0 {
+                break;
+            }
+            for entry in buffer[..read].chunks_exact(BLOCK_ENTRY_SIZE) {
+                let hash = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let references = u64::from_le_bytes(entry[8..].try_into().unwrap());
+                if references == 0 {
+                    state.free_slots.push(state.slot_count);
+                } else {
+                    state
+                        .slots_by_hash
+                        .entry(hash)
+                        .or_default()
+                        .push(state.slot_count);
+                }
+                state.slot_count += 1;
+            }
+        }
+
+        let deduplicated = Self {
+            inner,
+            map,
+            blocks,
+            granule,
+            state: Mutex::new(state),
+        };
+        deduplicated.write_header(&deduplicated.lock())?;
+        Ok(deduplicated)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_header(&self, state: &State) -> io::Result<()> {
+        let mut header = [0; HEADER_SIZE];
+        header[..8].copy_from_slice(&state.len.to_le_bytes());
+        header[8..12].copy_from_slice(&state.granule.to_le_bytes());
+        self.map.write_all_at(&header, 0)
+    }
+
+    /// Slot holding a granule, or `None` if it reads as zeroes
+    fn slot(&self, index: u64) -> io::Result<Option<u64>> {
+        let mut entry = [0; MAP_ENTRY_SIZE];
+        if self.map.read_at(&mut entry, map_offset(index))? < entry.len() {
+            return Ok(None);
+        }
+        Ok(u64::from_le_bytes(entry).checked_sub(1))
+    }
+
+    fn set_slot(&self, index: u64, slot: Option<u64>) -> io::Result<()> {
+        self.map.write_all_at(
+            &slot.map_or(0, |slot| slot + 1).to_le_bytes(),
+            map_offset(index),
+        )
+    }
+
+    fn block_entry(&self, slot: u64) -> io::Result<(u64, u64)> {
+        let mut entry = [0; BLOCK_ENTRY_SIZE];
+        self.blocks.read_exact_at(&mut entry, block_offset(slot))?;
+        Ok((
+            u64::from_le_bytes(entry[..8].try_into().unwrap()),
+            u64::from_le_bytes(entry[8..].try_into().unwrap()),
+        ))
+    }
+
+    fn set_block_entry(&self, slot: u64, hash: u64, references: u64) -> io::Result<()> {
+        let mut entry = [0; BLOCK_ENTRY_SIZE];
+        entry[..8].copy_from_slice(&hash.to_le_bytes());
+        entry[8..].copy_from_slice(&references.to_le_bytes());
+        self.blocks.write_all_at(&entry, block_offset(slot))
+    }
+
+    fn read_slot(&self, slot: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; self.granule as usize];
+        let mut read = 0;
+        while read < data.len() {
+            match self
+                .inner
+                .read_at(&mut data[read..], slot * self.granule_size() + read as u64)?
+            {
+                0 => break,
+                len => read += len,
+            }
+        }
+        Ok(data)
+    }
+
+    /// Find a slot that already holds `data`, comparing contents in case of hash collisions
+    fn find_slot(&self, state: &State, hash: u64, data: &[u8]) -> io::Result<Option<u64>> {
+        for &slot in state.slots_by_hash.get(&hash).into_iter().flatten() {
+            if self.read_slot(slot)? == data {
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    fn release(&self, state: &mut State, slot: u64) -> io::Result<()> {
+        let (hash, references) = self.block_entry(slot)?;
+        if references > 1 {
+            return self.set_block_entry(slot, hash, references - 1);
+        }
+
+        self.set_block_entry(slot, 0, 0)?;
+        if let Some(slots) = state.slots_by_hash.get_mut(&hash) {
+            slots.retain(|&other| other != slot);
+            if slots.is_empty() {
+                state.slots_by_hash.remove(&hash);
+            }
+        }
+        state.free_slots.push(slot);
+        self.inner
+            .punch_hole(slot * self.granule_size(), self.granule_size())
+    }
+}
+
+impl Granular for Deduplicated {
+    type State = State;
+
+    fn granule(&self) -> u32 {
+        self.granule
+    }
+
+    fn read_granule(&self, _: &mut State, index: u64) -> io::Result<Option<Vec<u8>>> {
+        match self.slot(index)? {
+            Some(slot) => self.read_slot(slot).map(Some),
+            None => Ok(Some(vec![0; self.granule as usize])),
+        }
+    }
+
+    fn write_granule(&self, state: &mut State, index: u64, data: &[u8]) -> io::Result<()> {
+        let old_slot = self.slot(index)?;
+        if data.iter().all(|&byte| byte == 0) {
+            return self.clear_granule(state, index);
+        }
+
+        let hash = checksum::hash(data);
+        if let Some(old_slot) = old_slot {
+            if self.block_entry(old_slot)?.0 == hash && self.read_slot(old_slot)? == data {
+                return Ok(());
+            }
+            self.release(state, old_slot)?;
+        }
+
+        let slot = if let Some(slot) = self.find_slot(state, hash, data)? {
+            let (_, references) = self.block_entry(slot)?;
+            self.set_block_entry(slot, hash, references + 1)?;
+            slot
+        } else {
+            let slot = state.free_slots.pop().unwrap_or_else(|| {
+                state.slot_count += 1;
+                state.slot_count - 1
+            });
+            self.inner.write_all_at(data, slot * self.granule_size())?;
+            self.set_block_entry(slot, hash, 1)?;
+            state.slots_by_hash.entry(hash).or_default().push(slot);
+            slot
+        };
+        self.set_slot(index, Some(slot))
+    }
+
+    fn clear_granule(&self, state: &mut State, index: u64) -> io::Result<()> {
+        if let Some(slot) = self.slot(index)? {
+            self.set_slot(index, None)?;
+            self.release(state, slot)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for Deduplicated {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.lock().len)
+    }
+
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.inner.metadata()
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.lock();
+        let len = usize::try_from(state.len.saturating_sub(offset))
+            .map_or(buffer.len(), |left| left.min(buffer.len()));
+        self.read_granules(&mut state, &mut buffer[..len], offset)
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        self.write_granules(&mut state, buffer, offset)?;
+
+        let end = offset + buffer.len() as u64;
+        if end > state.len {
+            state.len = end;
+            self.write_header(&state)?;
+        }
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        let len = len.min(state.len.saturating_sub(offset));
+        self.zero_granules(&mut state, offset, len)
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        if len > state.len {
+            state.len = len;
+            self.write_header(&state)?;
+        }
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        if len < state.len {
+            let old_len = state.len;
+            self.zero_granules(&mut state, len, old_len - len)?;
+            self.map
+                .set_len(map_offset(len.div_ceil(self.granule_size())))?;
+        }
+        state.len = len;
+        self.write_header(&state)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()?;
+        self.map.sync_data()?;
+        self.blocks.sync_data()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.inner.sync_all()?;
+        self.map.sync_all()?;
+        self.blocks.sync_all()
+    }
+
+    fn compression(&self) -> io::Result<Option<(u64, u64)>> {
+        self.inner.compression()
+    }
+
+    fn deduplication(&self) -> io::Result<Option<(u64, u64)>> {
+        let mut buffer = vec![0; 4096 * MAP_ENTRY_SIZE];
+        let mut referenced = 0;
+        let mut offset = map_offset(0);
+        loop {
+            let read = self.map.read_at(&mut buffer, offset)?;
+            if read == 0 {
+                break;
+            }
+            referenced += buffer[..read]
+                .chunks_exact(MAP_ENTRY_SIZE)
+                .filter(|entry| entry.iter().any(|&byte| byte != 0))
+                .count() as u64;
+            offset += read as u64;
+        }
+
+        let state = self.lock();
+        let unique = state.slot_count - state.free_slots.len() as u64;
+        Ok(Some((referenced, unique)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::file::FileStorage, temp_path::TempPath};
+
+    const GRANULE: u32 = 512;
+
+    fn open(path: &TempPath) -> Deduplicated {
+        Deduplicated::open(
+            Box::new(FileStorage::file(path.open())),
+            &crate::with_suffix(path, ".dedup"),
+            &crate::with_suffix(path, ".dedup-blocks"),
+            GRANULE,
+            true,
+        )
+        .unwrap()
+    }
+
+    fn write(storage: &Deduplicated, index: u64, byte: u8) {
+        storage
+            .write_all_at(&[byte; GRANULE as usize], index * u64::from(GRANULE))
+            .unwrap();
+    }
+
+    fn read(storage: &Deduplicated, index: u64) -> Vec<u8> {
+        let mut buffer = vec![0; GRANULE as usize];
+        storage
+            .read_at(&mut buffer, index * u64::from(GRANULE))
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn shared_slot_is_kept_until_last_reference_is_released() {
+        let path = TempPath::new("deduplicated-release");
+        let storage = open(&path);
+        write(&storage, 0, 1);
+        write(&storage, 1, 1);
+        write(&storage, 2, 2);
+        assert_eq!(storage.slot(0).unwrap(), storage.slot(1).unwrap());
+        assert_eq!(storage.block_entry(0).unwrap().1, 2);
+        assert_eq!(storage.deduplication().unwrap(), Some((3, 2)));
+
+        write(&storage, 0, 0);
+        assert_eq!(storage.slot(0).unwrap(), None);
+        assert_eq!(storage.block_entry(0).unwrap().1, 1);
+        assert_eq!(read(&storage, 1), vec![1; GRANULE as usize]);
+
+        storage
+            .punch_hole(u64::from(GRANULE), u64::from(GRANULE))
+            .unwrap();
+        assert_eq!(storage.block_entry(0).unwrap(), (0, 0));
+        assert_eq!(storage.deduplication().unwrap(), Some((1, 1)));
+        assert_eq!(read(&storage, 2), vec![2; GRANULE as usize]);
+    }
+
+    #[test]
+    fn released_slots_are_reused() {
+        let path = TempPath::new("deduplicated-reuse");
+        let storage = open(&path);
+        write(&storage, 0, 1);
+        write(&storage, 1, 2);
+        // overwriting the only reference frees the slot before the new data needs one
+        write(&storage, 0, 3);
+        assert_eq!(storage.lock().slot_count, 2);
+        assert_eq!(read(&storage, 0), vec![3; GRANULE as usize]);
+
+        write(&storage, 1, 0);
+        drop(storage);
+
+        // free slots are found again from the reference counts after reopening
+        let storage = open(&path);
+        assert_eq!(storage.lock().free_slots, [1]);
+        write(&storage, 4, 4);
+        assert_eq!(storage.slot(4).unwrap(), Some(1));
+        assert_eq!(storage.lock().slot_count, 2);
+        assert_eq!(read(&storage, 0), vec![3; GRANULE as usize]);
+        assert_eq!(read(&storage, 4), vec![4; GRANULE as usize]);
+    }
+}
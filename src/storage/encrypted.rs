@@ -0,0 +1,577 @@
+use super::{Granular, Storage};
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Generate, Payload},
+};
+use lru::LruCache;
+use std::{
+    fs, io,
+    num::NonZeroUsize,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// Large enough for random nonces to never repeat over every write a session could see
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+const KEY_CHECK: &[u8] = b"overmask key check";
+/// Decrypted maps of written granules kept in memory
+const CACHED_MAPS: usize = 256;
+/// Set in the associated data of maps, so they can't be swapped with granules
+const MAP_DOMAIN: u8 = 0x80;
+
+/// Authenticated encryption of fixed-size granules (one per block) with a session key
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: XChaCha20Poly1305,
+    granule: u32,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; 32], granule: u32) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&Key::from(*key)),
+            granule,
+        }
+    }
+
+    fn slot_size(&self) -> u64 {
+        u64::from(self.granule) + (NONCE_SIZE + TAG_SIZE) as u64
+    }
+
+    /// Granules covered by a map (a granule-sized bitmap)
+    fn group_granules(&self) -> u64 {
+        u64::from(self.granule) * 8
+    }
+
+    /// Size of a map followed by the slots of the granules it covers
+    fn group_size(&self) -> u64 {
+        (self.group_granules() + 1) * self.slot_size()
+    }
+
+    /// Size of the underlying storage needed for `len` bytes of plaintext
+    pub fn physical_len(&self, len: u64) -> u64 {
+        let granules = len.div_ceil(u64::from(self.granule));
+        (granules + granules.div_ceil(self.group_granules())) * self.slot_size()
+    }
+
+    pub fn logical_len(&self, physical_len: u64) -> u64 {
+        let slots = physical_len / self.slot_size();
+        let groups = slots / (self.group_granules() + 1);
+        let rest = slots % (self.group_granules() + 1);
+        (groups * self.group_granules() + rest.saturating_sub(1)) * u64::from(self.granule)
+    }
+
+    /// Tag of a fixed message, stored with the session to detect a wrong key early
+    pub fn check_value(&self) -> io::Result<Vec<u8>> {
+        self.cipher
+            .encrypt(
+                &XNonce::default(),
+                Payload {
+                    msg: &[],
+                    aad: KEY_CHECK,
+                },
+            )
+            .map_err(io::Error::other)
+    }
+}
+
+/// Maps of which granules hold encrypted data, one per group of granules
+struct Maps {
+    /// Groups whose map has been written, which are all groups within the underlying storage
+    /// (counted from its length when first needed)
+    groups: Option<u64>,
+    cached: LruCache<u64, Vec<u8>>,
+}
+
+/// Storage whose contents are encrypted and authenticated granule by granule
+///
+/// Each granule is stored in a slot of nonce | ciphertext | tag. Every group of slots is preceded
+/// by an encrypted map with a bit per granule that's set once the granule is written, so unwritten
+/// and punched granules stay holes that read back as zeroes, while zeroing a written slot (or a
+/// map) is caught as tampering instead of reading back as zeroes.
+pub struct Encrypted {
+    inner: Box<dyn Storage>,
+    cipher: Cipher,
+    domain: u8,
+    maps: Mutex<Maps>,
+}
+
+impl Encrypted {
+    pub fn overlay(inner: Box<dyn Storage>, cipher: Cipher) -> Self {
+        Self::new(inner, cipher, 0)
+    }
+
+    pub fn mask(inner: Box<dyn Storage>, cipher: Cipher) -> Self {
+        Self::new(inner, cipher, 1)
+    }
+
+    fn new(inner: Box<dyn Storage>, cipher: Cipher, domain: u8) -> Self {
+        Self {
+            inner,
+            cipher,
+            domain,
+            maps: Mutex::new(Maps {
+                groups: None,
+                cached: LruCache::new(NonZeroUsize::new(CACHED_MAPS).unwrap()),
+            }),
+        }
+    }
+
+    /// Write empty maps over all of the underlying storage, which has to be done before using
+    /// storage that doesn't start out empty (a container region)
+    pub fn format(&self) -> io::Result<()> {
+        let groups = self.inner.len()?.div_ceil(self.cipher.group_size());
+        let mut maps = self.lock_maps();
+        maps.groups = Some(0);
+        maps.cached.clear();
+        self.add_groups(&mut maps, groups)
+    }
+
+    fn slot_offset(&self, index: u64) -> u64 {
+        let group_granules = self.cipher.group_granules();
+        index / group_granules * self.cipher.group_size()
+            + (1 + index % group_granules) * self.cipher.slot_size()
+    }
+
+    /// Binds a slot to its position and to the overlay or mask, so slots can't be swapped around
+    fn associated_data(domain: u8, index: u64) -> [u8; 9] {
+        let mut associated_data = [domain; 9];
+        associated_data[1..].copy_from_slice(&index.to_le_bytes());
+        associated_data
+    }
+
+    fn seal(&self, plaintext: &[u8], associated_data: &[u8], offset: u64) -> io::Result<()> {
+        let nonce = XNonce::generate();
+        let ciphertext = self
+            .cipher
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(io::Error::other)?;
+
+        let mut slot = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        slot.extend_from_slice(&nonce);
+        slot.extend_from_slice(&ciphertext);
+        self.inner.write_all_at(&slot, offset)
+    }
+
+    /// Read the slot at `offset`, or return `None` if it's past the end of the underlying storage
+    fn read_slot(&self, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut slot = vec![0; usize::try_from(self.cipher.slot_size()).map_err(io::Error::other)?];
+        let mut read = 0;
+        while read < slot.len() {
+            match self
+                .inner
+                .read_at(&mut slot[read..], offset + read as u64)?
+            {
+                0 => break,
+                len => read += len,
+            }
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+        slot.truncate(read);
+        Ok(Some(slot))
+    }
+
+    fn unseal(&self, slot: &[u8], associated_data: &[u8]) -> Option<Vec<u8>> {
+        if slot.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = slot.split_at(NONCE_SIZE);
+        self.cipher
+            .cipher
+            .decrypt(
+                &XNonce::try_from(nonce).ok()?,
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .ok()
+    }
+
+    fn lock_maps(&self) -> MutexGuard<'_, Maps> {
+        self.maps.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn groups(&self, maps: &mut Maps) -> io::Result<u64> {
+        if let Some(groups) = maps.groups {
+            return Ok(groups);
+        }
+        let groups = self.inner.len()?.div_ceil(self.cipher.group_size());
+        maps.groups = Some(groups);
+        Ok(groups)
+    }
+
+    /// Write empty maps for every group up to `groups` that doesn't have one yet
+    fn add_groups(&self, maps: &mut Maps, groups: u64) -> io::Result<()> {
+        let empty = vec![0; self.cipher.granule as usize];
+        for group in self.groups(maps)?..groups {
+            self.write_map(group, &empty)?;
+            maps.cached.put(group, empty.clone());
+            maps.groups = Some(group + 1);
+        }
+        Ok(())
+    }
+
+    fn write_map(&self, group: u64, map: &[u8]) -> io::Result<()> {
+        self.seal(
+            map,
+            &Self::associated_data(self.domain | MAP_DOMAIN, group),
+            group * self.cipher.group_size(),
+        )
+    }
+
+    fn map<'a>(&self, maps: &'a mut Maps, group: u64) -> io::Result<&'a mut Vec<u8>> {
+        if !maps.cached.contains(&group) {
+            let map = if group >= self.groups(maps)? {
+                vec![0; self.cipher.granule as usize]
+            } else {
+                self.read_slot(group * self.cipher.group_size())?
+                    .and_then(|slot| {
+                        self.unseal(
+                            &slot,
+                            &Self::associated_data(self.domain | MAP_DOMAIN, group),
+                        )
+                    })
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "map of encrypted blocks from {} failed authentication (wrong key or tampered data)",
+                                group * self.cipher.group_granules()
+                            ),
+                        )
+                    })?
+            };
+            maps.cached.put(group, map);
+        }
+        Ok(maps.cached.get_mut(&group).unwrap())
+    }
+
+    /// Position of a granule's bit in its group's map
+    #[allow(clippy::cast_possible_truncation)]
+    fn bit(&self, index: u64) -> (u64, usize, u8) {
+        let within = index % self.cipher.group_granules();
+        (
+            index / self.cipher.group_granules(),
+            (within / 8) as usize,
+            1 << (within % 8),
+        )
+    }
+
+    fn is_written(&self, index: u64) -> io::Result<bool> {
+        let (group, byte, bit) = self.bit(index);
+        let mut maps = self.lock_maps();
+        Ok(self.map(&mut maps, group)?[byte] & bit != 0)
+    }
+
+    /// Clear the bits of granules `first..end`, writing back the maps that changed
+    fn clear_bits(&self, maps: &mut Maps, first: u64, end: u64) -> io::Result<()> {
+        let mut index = first;
+        while index < end {
+            let (group, _, _) = self.bit(index);
+            let group_end = ((group + 1) * self.cipher.group_granules()).min(end);
+            if group < self.groups(maps)? {
+                let map = self.map(maps, group)?;
+                let mut changed = false;
+                for index in index..group_end {
+                    let (_, byte, bit) = self.bit(index);
+                    changed |= map[byte] & bit != 0;
+                    map[byte] &= !bit;
+                }
+                if changed {
+                    let map = map.clone();
+                    self.write_map(group, &map)?;
+                }
+            }
+            index = group_end;
+        }
+        Ok(())
+    }
+
+    /// Zero a range that lies within a single granule, if that granule holds data
+    fn zero_partial(&self, start: u64, end: u64) -> io::Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        let index = start / self.granule_size();
+        if let Some(mut plaintext) = self.read_granule(&mut (), index)?
+            && self.is_written(index)?
+        {
+            plaintext[self.within(start)..=self.within(end - 1)].fill(0);
+            self.write_granule(&mut (), index, &plaintext)?;
+        }
+        Ok(())
+    }
+
+    /// Turn granules `first..end` back into holes
+    fn punch_granules(&self, first: u64, end: u64) -> io::Result<()> {
+        let mut maps = self.lock_maps();
+        // the maps are cleared first, so an interrupted punch never leaves a written granule zeroed
+        self.clear_bits(&mut maps, first, end)?;
+        let mut index = first;
+        while index < end {
+            let group_end =
+                (index / self.cipher.group_granules() + 1) * self.cipher.group_granules();
+            let group_end = group_end.min(end);
+            self.inner.punch_hole(
+                self.slot_offset(index),
+                (group_end - index) * self.cipher.slot_size(),
+            )?;
+            index = group_end;
+        }
+        Ok(())
+    }
+}
+
+/// The maps are locked as needed by each granule, so there's no state to hold across a write
+impl Granular for Encrypted {
+    type State = ();
+
+    fn granule(&self) -> u32 {
+        self.cipher.granule
+    }
+
+    fn read_granule(&self, (): &mut (), index: u64) -> io::Result<Option<Vec<u8>>> {
+        let Some(slot) = self.read_slot(self.slot_offset(index))? else {
+            return Ok(None);
+        };
+        if !self.is_written(index)? {
+            return Ok(Some(vec![0; self.cipher.granule as usize]));
+        }
+
+        self.unseal(&slot, &Self::associated_data(self.domain, index))
+            .map(Some)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "encrypted block {index} failed authentication (wrong key or tampered data)"
+                    ),
+                )
+            })
+    }
+
+    fn write_granule(&self, (): &mut (), index: u64, plaintext: &[u8]) -> io::Result<()> {
+        let (group, byte, bit) = self.bit(index);
+        let mut maps = self.lock_maps();
+        self.add_groups(&mut maps, group + 1)?;
+        self.seal(
+            plaintext,
+            &Self::associated_data(self.domain, index),
+            self.slot_offset(index),
+        )?;
+
+        // the map is only updated after the granule, so an interrupted write leaves a hole
+        let map = self.map(&mut maps, group)?;
+        if map[byte] & bit == 0 {
+            map[byte] |= bit;
+            let map = map.clone();
+            self.write_map(group, &map)?;
+        }
+        Ok(())
+    }
+
+    fn clear_granule(&self, (): &mut (), index: u64) -> io::Result<()> {
+        self.punch_granules(index, index + 1)
+    }
+}
+
+impl Storage for Encrypted {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.cipher.logical_len(self.inner.len()?))
+    }
+
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.inner.metadata()
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_granules(&mut (), buffer, offset)
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        self.write_granules(&mut (), buffer, offset)
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        let granule_size = self.granule_size();
+        let end = offset + len;
+        let first_whole = offset.div_ceil(granule_size);
+        let last_whole = end / granule_size;
+
+        if len == 0 {
+            return Ok(());
+        }
+        if offset / granule_size == (end - 1) / granule_size {
+            return self.zero_partial(offset, end);
+        }
+        self.zero_partial(offset, first_whole * granule_size)?;
+        if first_whole < last_whole {
+            self.punch_granules(first_whole, last_whole)?;
+        }
+        self.zero_partial(last_whole * granule_size, end)
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        let groups = len
+            .div_ceil(self.granule_size())
+            .div_ceil(self.cipher.group_granules());
+        self.add_groups(&mut self.lock_maps(), groups)?;
+        self.inner.allocate(self.cipher.physical_len(len))
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let granule_size = self.granule_size();
+        if !len.is_multiple_of(granule_size) {
+            self.zero_partial(len, len.next_multiple_of(granule_size))?;
+        }
+
+        let granules = len.div_ceil(granule_size);
+        let groups = granules.div_ceil(self.cipher.group_granules());
+        let mut maps = self.lock_maps();
+        if groups >= self.groups(&mut maps)? {
+            self.add_groups(&mut maps, groups)?;
+        } else {
+            let dropped: Vec<u64> = maps
+                .cached
+                .iter()
+                .map(|(&group, _)| group)
+                .filter(|&group| group >= groups)
+                .collect();
+            for group in dropped {
+                maps.cached.pop(&group);
+            }
+            maps.groups = Some(groups);
+        }
+        // granules cut off from the last group would otherwise be marked written once it grows again
+        self.clear_bits(&mut maps, granules, groups * self.cipher.group_granules())?;
+        self.inner.set_len(self.cipher.physical_len(len))?;
+
+        // a container region keeps its size, so the groups past the end get their maps back
+        let inner_groups = self.inner.len()?.div_ceil(self.cipher.group_size());
+        self.add_groups(&mut maps, inner_groups)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.inner.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::file::FileStorage, temp_path::TempPath};
+    use std::{fs::File, os::unix::fs::FileExt};
+
+    const GRANULE: u32 = 16;
+
+    fn encrypted(file: &File) -> Encrypted {
+        Encrypted::overlay(
+            Box::new(FileStorage::file(file.try_clone().unwrap())),
+            Cipher::new(&[7; 32], GRANULE),
+        )
+    }
+
+    fn read(storage: &Encrypted, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; len];
+        let read = storage.read_at(&mut buffer, offset)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    #[test]
+    fn holes_read_as_zeroes() {
+        let path = TempPath::new("encrypted-holes");
+        let file = path.open();
+        let storage = encrypted(&file);
+        storage.write_all_at(&[1; 16], 300 * 16).unwrap();
+        assert_eq!(read(&storage, 0, 32).unwrap(), vec![0; 32]);
+        assert_eq!(read(&storage, 299 * 16, 48).unwrap()[16..32], [1; 16]);
+
+        // a new instance has to find the written granule through the stored maps
+        let storage = encrypted(&file);
+        assert_eq!(read(&storage, 300 * 16, 16).unwrap(), vec![1; 16]);
+        assert_eq!(read(&storage, 200 * 16, 16).unwrap(), vec![0; 16]);
+    }
+
+    #[test]
+    fn punched_granules_read_as_zeroes() {
+        let path = TempPath::new("encrypted-punch");
+        let file = path.open();
+        let storage = encrypted(&file);
+        storage.write_all_at(&[2; 64], 0).unwrap();
+        storage.punch_hole(8, 40).unwrap();
+        let mut expected = vec![2; 64];
+        expected[8..48].fill(0);
+        assert_eq!(read(&storage, 0, 64).unwrap(), expected);
+        assert_eq!(read(&encrypted(&file), 0, 64).unwrap(), expected);
+    }
+
+    #[test]
+    fn zeroed_granule_fails_authentication() {
+        let path = TempPath::new("encrypted-zeroed-granule");
+        let file = path.open();
+        let storage = encrypted(&file);
+        storage.write_all_at(&[3; 32], 0).unwrap();
+        let cipher = Cipher::new(&[7; 32], GRANULE);
+        let slot = vec![0; usize::try_from(cipher.slot_size()).unwrap()];
+        file.write_all_at(&slot, cipher.slot_size()).unwrap();
+
+        let error = read(&encrypted(&file), 0, 16).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn zeroed_map_fails_authentication() {
+        let path = TempPath::new("encrypted-zeroed-map");
+        let file = path.open();
+        let storage = encrypted(&file);
+        storage.write_all_at(&[4; 16], 0).unwrap();
+        let cipher = Cipher::new(&[7; 32], GRANULE);
+        let slot = vec![0; usize::try_from(cipher.slot_size()).unwrap()];
+        file.write_all_at(&slot, 0).unwrap();
+
+        let error = read(&encrypted(&file), 0, 16).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn region_keeps_maps_when_shrunk() {
+        let path = TempPath::new("encrypted-region");
+        let file = path.open();
+        let cipher = Cipher::new(&[7; 32], GRANULE);
+        let capacity = cipher.physical_len(300 * 16);
+        let region = || {
+            Encrypted::overlay(
+                Box::new(FileStorage::region(file.try_clone().unwrap(), 0, capacity)),
+                cipher.clone(),
+            )
+        };
+        let storage = region();
+        storage.format().unwrap();
+        storage.write_all_at(&[5; 16], 200 * 16).unwrap();
+        storage.set_len(0).unwrap();
+        assert_eq!(read(&region(), 200 * 16, 16).unwrap(), vec![0; 16]);
+    }
+
+    #[test]
+    fn lengths_round_trip() {
+        let cipher = Cipher::new(&[7; 32], GRANULE);
+        for len in [0, 16, 128 * 16, 129 * 16, 1000 * 16] {
+            assert_eq!(cipher.logical_len(cipher.physical_len(len)), len);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use super::Storage;
+use nix::fcntl::{FallocateFlags, fallocate};
+use std::{fs, io, os::unix::fs::FileExt};
+
+/// A byte range backed by either a whole file or a fixed-size region of a container
+pub struct FileStorage {
+    file: fs::File,
+    base: u64,
+    capacity: Option<u64>,
+}
+
+impl FileStorage {
+    pub fn file(file: fs::File) -> Self {
+        Self {
+            file,
+            base: 0,
+            capacity: None,
+        }
+    }
+
+    pub fn region(file: fs::File, base: u64, capacity: u64) -> Self {
+        Self {
+            file,
+            base,
+            capacity: Some(capacity),
+        }
+    }
+
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        match self.capacity {
+            Some(capacity) => {
+                usize::try_from(capacity.saturating_sub(offset)).map_or(len, |left| left.min(len))
+            }
+            None => len,
+        }
+    }
+
+    fn fallocate(&self, flags: FallocateFlags, offset: u64, len: u64) -> io::Result<()> {
+        fallocate(
+            &self.file,
+            flags,
+            (self.base + offset).try_into().map_err(io::Error::other)?,
+            len.try_into().map_err(io::Error::other)?,
+        )?;
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn len(&self) -> io::Result<u64> {
+        match self.capacity {
+            Some(capacity) => Ok(capacity),
+            None => Ok(self.file.metadata()?.len()),
+        }
+    }
+
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.file.metadata()
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = self.clamp(offset, buffer.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        self.file.read_at(&mut buffer[..len], self.base + offset)
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        if self.clamp(offset, buffer.len()) < buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "write past the end of container region",
+            ));
+        }
+        self.file.write_all_at(buffer, self.base + offset)
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        let len = match self.capacity {
+            Some(capacity) => len.min(capacity.saturating_sub(offset)),
+            None => len,
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        self.fallocate(
+            FallocateFlags::FALLOC_FL_KEEP_SIZE | FallocateFlags::FALLOC_FL_PUNCH_HOLE,
+            offset,
+            len,
+        )
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        self.fallocate(FallocateFlags::empty(), 0, len)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        match self.capacity {
+            Some(capacity) => self.punch_hole(len, capacity.saturating_sub(len)),
+            None => self.file.set_len(len),
+        }
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
@@ -0,0 +1,169 @@
+mod compressed;
+mod deduplicated;
+mod encrypted;
+mod file;
+
+pub use compressed::Compressed;
+pub use deduplicated::Deduplicated;
+pub use encrypted::Cipher;
+use encrypted::Encrypted;
+pub use file::FileStorage;
+
+use std::{fs, io};
+
+/// A byte range that session data (the overlay or the mask) is stored in
+pub trait Storage: Send + Sync {
+    fn len(&self) -> io::Result<u64>;
+
+    fn metadata(&self) -> io::Result<fs::Metadata>;
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Deallocate a range, which reads back as zeroes afterwards
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()>;
+
+    fn allocate(&self, len: u64) -> io::Result<()>;
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    fn sync_data(&self) -> io::Result<()>;
+
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Bytes of data stored and the space they take up, if the storage is compressed
+    fn compression(&self) -> io::Result<Option<(u64, u64)>> {
+        Ok(None)
+    }
+
+    /// Blocks that hold data and how many unique blocks they share, if the storage deduplicates
+    fn deduplication(&self) -> io::Result<Option<(u64, u64)>> {
+        Ok(None)
+    }
+}
+
+/// Wrap the overlay and mask of a session in encryption if the session is encrypted
+pub fn with_cipher(
+    overlay: Box<dyn Storage>,
+    mask: Box<dyn Storage>,
+    cipher: Option<&Cipher>,
+    format: bool,
+) -> io::Result<(Box<dyn Storage>, Box<dyn Storage>)> {
+    let Some(cipher) = cipher else {
+        return Ok((overlay, mask));
+    };
+    let overlay = Encrypted::overlay(overlay, cipher.clone());
+    let mask = Encrypted::mask(mask, cipher.clone());
+    if format {
+        overlay.format()?;
+        mask.format()?;
+    }
+    Ok((Box::new(overlay), Box::new(mask)))
+}
+
+/// Storage kept in fixed-size granules that are each read and written whole, with `State` being
+/// whatever the storage keeps locked while doing so
+trait Granular {
+    type State;
+
+    fn granule(&self) -> u32;
+
+    /// Contents of a granule, or `None` if it's past the end of the storage
+    fn read_granule(&self, state: &mut Self::State, index: u64) -> io::Result<Option<Vec<u8>>>;
+
+    fn write_granule(&self, state: &mut Self::State, index: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Make a whole granule read as zeroes
+    fn clear_granule(&self, state: &mut Self::State, index: u64) -> io::Result<()>;
+
+    fn granule_size(&self) -> u64 {
+        u64::from(self.granule())
+    }
+
+    /// Position of a byte within its granule
+    #[allow(clippy::cast_possible_truncation)]
+    fn within(&self, position: u64) -> usize {
+        (position % self.granule_size()) as usize
+    }
+
+    /// Read up to `buffer.len()` bytes, stopping early at the end of the storage
+    fn read_granules(
+        &self,
+        state: &mut Self::State,
+        buffer: &mut [u8],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let within = self.within(position);
+            let Some(data) = self.read_granule(state, position / self.granule_size())? else {
+                break;
+            };
+
+            let chunk = (data.len() - within).min(buffer.len() - done);
+            buffer[done..done + chunk].copy_from_slice(&data[within..within + chunk]);
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    /// Write whole granules directly, and the partial granules at either end by reading them first
+    fn write_granules(
+        &self,
+        state: &mut Self::State,
+        buffer: &[u8],
+        offset: u64,
+    ) -> io::Result<()> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let index = position / self.granule_size();
+            let within = self.within(position);
+
+            let len = (self.granule() as usize - within).min(buffer.len() - done);
+            if len == self.granule() as usize {
+                self.write_granule(state, index, &buffer[done..done + len])?;
+            } else {
+                self.modify_granule(state, index, within, &buffer[done..done + len])?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Replace part of a granule, keeping the rest of its existing contents
+    fn modify_granule(
+        &self,
+        state: &mut Self::State,
+        index: u64,
+        within: usize,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let mut data = self
+            .read_granule(state, index)?
+            .unwrap_or_else(|| vec![0; self.granule() as usize]);
+        data[within..within + bytes.len()].copy_from_slice(bytes);
+        self.write_granule(state, index, &data)
+    }
+
+    /// Zero a range, clearing whole granules without reading them
+    fn zero_granules(&self, state: &mut Self::State, offset: u64, len: u64) -> io::Result<()> {
+        let end = offset + len;
+        let mut position = offset;
+        while position < end {
+            let index = position / self.granule_size();
+            let within = self.within(position);
+            let chunk = (self.granule_size() - within as u64).min(end - position);
+            if chunk == self.granule_size() {
+                self.clear_granule(state, index)?;
+            } else {
+                let zeroes = vec![0; usize::try_from(chunk).map_err(io::Error::other)?];
+                self.modify_granule(state, index, within, &zeroes)?;
+            }
+            position += chunk;
+        }
+        Ok(())
+    }
+}
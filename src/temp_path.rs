@@ -0,0 +1,80 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A path in the temporary directory for a test, removed on drop along with every sidecar file
+/// named after it (`<path>.map`, `<path>.index`, ...), even if the test panics
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        let path =
+            Self(std::env::temp_dir().join(format!("overmask-{name}-{}", std::process::id())));
+        // leftovers of an earlier run that was killed before it could clean up
+        path.remove();
+        path
+    }
+
+    /// Create the path as a file holding `contents`
+    pub fn with_contents(name: &str, contents: &[u8]) -> Self {
+        let path = Self::new(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Open the path as a read-write file, creating it if needed
+    pub fn open(&self) -> fs::File {
+        fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.0)
+            .unwrap()
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        } else {
+            let _ = fs::remove_file(&self.0);
+        }
+
+        let (Some(parent), Some(name)) = (self.0.parent(), self.0.file_name()) else {
+            return;
+        };
+        let mut prefix = name.to_owned();
+        prefix.push(".");
+        for entry in fs::read_dir(parent).into_iter().flatten().flatten() {
+            if entry
+                .file_name()
+                .as_encoded_bytes()
+                .starts_with(prefix.as_encoded_bytes())
+            {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
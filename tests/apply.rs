@@ -0,0 +1,84 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+const SIZE: usize = 64 * 1024 * 1024;
+
+fn overmask(directory: &PathBuf) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_overmask"));
+    command
+        .current_dir(directory)
+        .args(["-s", "seed", "-o", "overlay", "-m", "mask"]);
+    command
+}
+
+/// Interrupt an apply of a session with a `seed_len` byte seed and a fully masked overlay of
+/// `SIZE` bytes, then resume it
+fn interrupt_and_resume(name: &str, seed_len: usize) {
+    let directory = std::env::temp_dir().join(format!("overmask-{name}-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let seed: Vec<u8> = (0..seed_len)
+        .map(|i| u8::try_from(i % 251).unwrap())
+        .collect();
+    fs::write(directory.join("seed"), &seed).unwrap();
+    assert!(overmask(&directory).arg("init").status().unwrap().success());
+    fs::write(directory.join("overlay"), vec![0x42; SIZE]).unwrap();
+    fs::write(directory.join("mask"), vec![0xff; SIZE]).unwrap();
+
+    // interrupt the apply once it has started writing to the seed
+    let mut apply = overmask(&directory)
+        .args(["--progress", "json", "apply", "--force"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(apply.stdout.take().unwrap()).lines();
+    for line in lines.by_ref() {
+        if line.unwrap().contains("\"operation\":\"applying\"") {
+            break;
+        }
+    }
+    assert!(
+        Command::new("kill")
+            .args(["-INT", &apply.id().to_string()])
+            .status()
+            .unwrap()
+            .success()
+    );
+    // keep reading progress, closing the pipe would make the apply fail instead
+    lines.for_each(drop);
+    assert_eq!(apply.wait().unwrap().code(), Some(130));
+    assert!(directory.join("mask.checkpoint").exists());
+    assert_ne!(fs::read(directory.join("seed")).unwrap(), vec![0x42; SIZE]);
+
+    let resume = overmask(&directory)
+        .args(["apply", "--force", "--resume"])
+        .output()
+        .unwrap();
+    assert!(
+        resume.status.success(),
+        "{}",
+        String::from_utf8_lossy(&resume.stderr)
+    );
+    assert!(String::from_utf8_lossy(&resume.stderr).contains("resuming from checkpoint"));
+    assert_eq!(fs::read(directory.join("seed")).unwrap(), vec![0x42; SIZE]);
+    assert!(!directory.join("mask.checkpoint").exists());
+
+    // the finished apply refreshes the manifest, so the changed seed is accepted again
+    assert!(overmask(&directory).arg("info").status().unwrap().success());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn interrupted_apply_resumes() {
+    interrupt_and_resume("apply", SIZE);
+}
+
+#[test]
+fn interrupted_apply_past_the_end_of_the_seed_resumes() {
+    // the seed file grows while applying, which mustn't invalidate the checkpoint
+    interrupt_and_resume("apply-grow", 4096);
+}